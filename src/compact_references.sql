@@ -0,0 +1,86 @@
+-- Converts the `refs` table into a compact layout, in which each name that
+-- is referenced in a file is stored once, with a count, and each reference
+-- only stores its position. `refs` is replaced by a view with the same
+-- columns, so queries are unaffected, and references are still inserted into
+-- and deleted from `refs` through the view's triggers.
+CREATE TABLE ref_names (
+  id INTEGER PRIMARY KEY,
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  name TEXT NOT NULL,
+  name_fold TEXT NOT NULL,
+  kind TEXT,
+  count UNSIGNED INTEGER NOT NULL
+);
+
+CREATE TABLE ref_positions (
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  row UNSIGNED INTEGER NOT NULL,
+  column UNSIGNED INTEGER NOT NULL,
+  length UNSIGNED INTEGER NOT NULL,
+  ref_name_id INTEGER NOT NULL REFERENCES ref_names (id) ON DELETE CASCADE,
+  PRIMARY KEY (file_id, row, column)
+);
+
+INSERT INTO ref_names
+  (file_id, name, name_fold, kind, count)
+SELECT
+  file_id, name, name_fold, kind, COUNT(*)
+FROM refs
+GROUP BY file_id, name, name_fold, kind;
+
+INSERT INTO ref_positions
+  (file_id, row, column, length, ref_name_id)
+SELECT
+  refs.file_id, refs.row, refs.column, refs.length, ref_names.id
+FROM refs, ref_names
+WHERE
+  ref_names.file_id = refs.file_id AND
+  ref_names.name = refs.name AND
+  ref_names.kind IS refs.kind;
+
+DROP TABLE refs;
+CREATE INDEX ref_name_folds ON ref_names (name_fold);
+CREATE INDEX ref_name_file_ids ON ref_names (file_id, name);
+CREATE INDEX ref_position_names ON ref_positions (ref_name_id);
+
+CREATE VIEW refs AS
+SELECT
+  ref_positions.file_id AS file_id,
+  ref_positions.row AS row,
+  ref_positions.column AS column,
+  ref_names.name AS name,
+  ref_names.name_fold AS name_fold,
+  ref_names.kind AS kind,
+  ref_positions.length AS length
+FROM ref_positions, ref_names
+WHERE ref_names.id = ref_positions.ref_name_id;
+
+CREATE TRIGGER insert_ref INSTEAD OF INSERT ON refs
+BEGIN
+  INSERT INTO ref_names
+    (file_id, name, name_fold, kind, count)
+  SELECT
+    NEW.file_id, NEW.name, NEW.name_fold, NEW.kind, 0
+  WHERE NOT EXISTS (
+    SELECT 1 FROM ref_names
+    WHERE file_id = NEW.file_id AND name = NEW.name AND kind IS NEW.kind
+  );
+  UPDATE ref_names SET count = count + 1
+  WHERE file_id = NEW.file_id AND name = NEW.name AND kind IS NEW.kind;
+  INSERT INTO ref_positions
+    (file_id, row, column, length, ref_name_id)
+  SELECT
+    NEW.file_id, NEW.row, NEW.column, NEW.length, id
+  FROM ref_names
+  WHERE file_id = NEW.file_id AND name = NEW.name AND kind IS NEW.kind;
+END;
+
+CREATE TRIGGER delete_ref INSTEAD OF DELETE ON refs
+BEGIN
+  DELETE FROM ref_positions
+  WHERE file_id = OLD.file_id AND row = OLD.row AND column = OLD.column;
+  UPDATE ref_names SET count = count - 1
+  WHERE file_id = OLD.file_id AND name = OLD.name AND kind IS OLD.kind;
+  DELETE FROM ref_names
+  WHERE file_id = OLD.file_id AND name = OLD.name AND kind IS OLD.kind AND count = 0;
+END;
@@ -0,0 +1,331 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+pub const PROJECT_CONFIG_FILE_NAME: &'static str = ".tree-tags.toml";
+
+const DEFAULT_GENERATED_MARKERS: [&'static str; 2] = [
+    r"Code generated .* DO NOT EDIT",
+    r"@generated",
+];
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(default)]
+    pub grammars: Vec<PathBuf>,
+    pub compiled_parsers_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub languages: HashMap<String, LanguageConfig>,
+    #[serde(default)]
+    pub language_globs: HashMap<String, String>,
+    pub generated_markers: Option<Vec<String>>,
+    #[serde(default)]
+    pub kind_priority: Vec<String>,
+    pub index: Option<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LanguageConfig {
+    #[serde(default)]
+    pub builtins: Vec<String>,
+    #[serde(default)]
+    pub ctags_kinds: HashMap<String, String>,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    pub index_references: Option<bool>,
+    #[serde(default)]
+    pub ignored_contexts: Vec<String>,
+    #[serde(default)]
+    pub file_modules: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let config: Self = match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(invalid_data)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e),
+        };
+        if let Some(index_name) = config.index.as_ref() {
+            validate_index_name(index_name)?;
+        }
+        Ok(config)
+    }
+
+    // Apply the settings from a project's config file on top of these ones.
+    // Projects are often checked out from untrusted sources, so their configs
+    // can only set the index, excludes, language options and language globs.
+    // Index names are validated, so the index stays within the `indexes`
+    // directory. Grammars would be compiled and loaded, and the other settings
+    // choose where files are written, so they are ignored with a warning.
+    // Exclude patterns are combined, resolved against the project's directory,
+    // and each language's settings replace the global ones as a whole.
+    pub fn merge(&mut self, project_config: Config, project_path: &Path) {
+        let ignored_settings = [
+            ("grammars", !project_config.grammars.is_empty()),
+            ("compiled-parsers-dir", project_config.compiled_parsers_dir.is_some()),
+            ("generated-markers", project_config.generated_markers.is_some()),
+            ("kind-priority", !project_config.kind_priority.is_empty()),
+        ];
+        for (name, is_set) in ignored_settings.iter() {
+            if *is_set {
+                eprintln!(
+                    "Ignoring the `{}` setting in {}",
+                    name,
+                    project_path.join(PROJECT_CONFIG_FILE_NAME).display()
+                );
+            }
+        }
+
+        if project_config.index.is_some() {
+            self.index = project_config.index;
+        }
+        self.exclude.extend(
+            project_config
+                .exclude
+                .into_iter()
+                .map(|pattern| project_path.join(pattern).to_string_lossy().into_owned()),
+        );
+        self.languages.extend(project_config.languages);
+        self.language_globs.extend(project_config.language_globs);
+    }
+
+    pub fn language(&self, name: &str) -> Option<&LanguageConfig> {
+        self.languages.get(name)
+    }
+
+    pub fn generated_markers(&self) -> Vec<&str> {
+        match self.generated_markers.as_ref() {
+            Some(markers) => markers.iter().map(|marker| marker.as_str()).collect(),
+            None => DEFAULT_GENERATED_MARKERS.to_vec(),
+        }
+    }
+
+    pub fn excluded_paths(&self) -> Result<Option<GlobSet>, globset::Error> {
+        if self.exclude.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in self.exclude.iter() {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(Some(builder.build()?))
+    }
+
+    pub fn languages_without_references(&self) -> Vec<String> {
+        self.languages
+            .iter()
+            .filter(|(_, language_config)| language_config.index_references == Some(false))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn ignored_contexts(&self) -> HashMap<String, Vec<String>> {
+        self.languages
+            .iter()
+            .filter(|(_, language_config)| !language_config.ignored_contexts.is_empty())
+            .map(|(name, language_config)| (name.clone(), language_config.ignored_contexts.clone()))
+            .collect()
+    }
+
+    pub fn file_module_languages(&self) -> Vec<String> {
+        self.languages
+            .iter()
+            .filter(|(_, language_config)| language_config.file_modules)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn case_insensitive_languages(&self) -> Vec<String> {
+        self.languages
+            .iter()
+            .filter(|(_, language_config)| language_config.case_insensitive)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+// Find the project config file in the given directory or the nearest of its
+// ancestors.
+pub fn find_project_config(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE_NAME))
+        .find(|config_path| config_path.is_file())
+}
+
+// Named indexes are stored in a single directory, so an index name must be a
+// plain file name, not a path that could refer to a file elsewhere.
+pub fn validate_index_name(name: &str) -> io::Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) if component == name => Ok(()),
+        _ => Err(invalid_data(format!("Invalid index name: {:?}", name))),
+    }
+}
+
+pub fn add_grammar(config_file_path: &Path, grammar_path: &Path) -> io::Result<bool> {
+    edit_grammars(config_file_path, |grammars| {
+        let grammar_path = toml::Value::String(path_to_string(grammar_path)?);
+        if grammars.contains(&grammar_path) {
+            Ok(false)
+        } else {
+            grammars.push(grammar_path);
+            Ok(true)
+        }
+    })
+}
+
+pub fn remove_grammar(config_file_path: &Path, grammar_path: &Path) -> io::Result<bool> {
+    edit_grammars(config_file_path, |grammars| {
+        let grammar_path = toml::Value::String(path_to_string(grammar_path)?);
+        let previous_len = grammars.len();
+        grammars.retain(|path| *path != grammar_path);
+        Ok(grammars.len() != previous_len)
+    })
+}
+
+// Edit the config file as a generic TOML document, so that settings
+// this version doesn't know about are preserved.
+fn edit_grammars<F>(config_file_path: &Path, f: F) -> io::Result<bool>
+where
+    F: FnOnce(&mut Vec<toml::Value>) -> io::Result<bool>,
+{
+    let mut document = match fs::read_to_string(config_file_path) {
+        Ok(contents) => contents.parse::<toml::Value>().map_err(invalid_data)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            toml::Value::Table(toml::value::Table::new())
+        }
+        Err(e) => return Err(e),
+    };
+
+    let table = document
+        .as_table_mut()
+        .ok_or_else(|| invalid_data("Config file is not a table"))?;
+    let grammars = table
+        .entry("grammars".to_owned())
+        .or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| invalid_data("The `grammars` setting is not an array"))?;
+
+    let changed = f(grammars)?;
+    if changed {
+        fs::write(
+            config_file_path,
+            toml::to_string(&document).map_err(invalid_data)?,
+        )?;
+    }
+    Ok(changed)
+}
+
+fn path_to_string(path: &Path) -> io::Result<String> {
+    path.to_str()
+        .map(|path| path.to_owned())
+        .ok_or_else(|| invalid_data("Grammar path is not valid UTF-8"))
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_find_project_config() {
+        let dir = TempDir::new();
+        let nested_path = dir.path().join("project/src/lib");
+        fs::create_dir_all(&nested_path).unwrap();
+        assert_eq!(find_project_config(&nested_path), None);
+
+        let project_config_path = dir.write("project/.tree-tags.toml", "");
+        assert_eq!(find_project_config(&nested_path), Some(project_config_path.clone()));
+        assert_eq!(
+            find_project_config(&dir.path().join("project")),
+            Some(project_config_path)
+        );
+
+        // The nearest config wins.
+        let src_config_path = dir.write("project/src/.tree-tags.toml", "");
+        assert_eq!(find_project_config(&nested_path), Some(src_config_path));
+    }
+
+    #[test]
+    fn test_merge_project_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+                grammars = ["/grammars/tree-sitter-ruby"]
+                exclude = ["/global/**"]
+                kind-priority = ["class"]
+                index = "work"
+
+                [language-globs]
+                "*.js.erb" = "ruby"
+
+                [languages.ruby]
+                builtins = ["puts"]
+
+                [languages.javascript]
+                builtins = ["console"]
+            "#,
+        )
+        .unwrap();
+        let project_config: Config = toml::from_str(
+            r#"
+                grammars = ["../tree-sitter-evil"]
+                compiled-parsers-dir = "/tmp"
+                exclude = ["vendor/**"]
+                kind-priority = ["function"]
+                index = "project"
+
+                [language-globs]
+                "*.es6" = "javascript"
+
+                [languages.javascript]
+                case-insensitive = true
+            "#,
+        )
+        .unwrap();
+        config.merge(project_config, Path::new("/project"));
+
+        assert_eq!(config.grammars, vec![PathBuf::from("/grammars/tree-sitter-ruby")]);
+        assert_eq!(config.compiled_parsers_dir, None);
+        assert_eq!(config.kind_priority, vec!["class".to_owned()]);
+        assert_eq!(config.index, Some("project".to_owned()));
+        assert_eq!(config.exclude, vec!["/global/**", "/project/vendor/**"]);
+        assert_eq!(config.language_globs.len(), 2);
+        assert_eq!(config.language_globs["*.es6"], "javascript");
+        assert_eq!(config.language("ruby").unwrap().builtins, vec!["puts".to_owned()]);
+        let javascript_config = config.language("javascript").unwrap();
+        assert!(javascript_config.builtins.is_empty());
+        assert!(javascript_config.case_insensitive);
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_index_names() {
+        let dir = TempDir::new();
+        let path = dir.write("config.toml", "index = \"../../x\"\n");
+        assert_eq!(Config::load(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let path = dir.write("config.toml", "index = \"work\"\n");
+        assert_eq!(Config::load(&path).unwrap().index, Some("work".to_owned()));
+    }
+
+    #[test]
+    fn test_validate_index_name() {
+        assert!(validate_index_name("work").is_ok());
+        assert!(validate_index_name("work.v2").is_ok());
+        for name in &["", ".", "..", "../work", "../../x", "a/b", "/tmp/work", "work/"] {
+            assert!(validate_index_name(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+}
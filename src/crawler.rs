@@ -1,32 +1,166 @@
-use crate::language_registry::LanguageRegistry;
-use crate::store::{Store, StoreFile};
+use crate::language_registry::{FileTypes, LanguageRegistry};
+use crate::store::{DefinitionRecord, Store, StoreFile};
+use globset::GlobSet;
 use ignore::{WalkBuilder, WalkState};
-use std::collections::HashMap;
+use regex::RegexSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::fs::File;
-use std::io::{self, Read};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tree_sitter::{Language, Parser, Point, PropertySheet, Tree, TreePropertyCursor};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tree_sitter::{InputEdit, Language, Parser, Point, PropertySheet, Tree, TreePropertyCursor};
+
+const BYTE_ORDER_MARK: char = '\u{feff}';
+const GENERATED_MARKER_LINE_COUNT: usize = 5;
+const BINARY_CHECK_BYTE_COUNT: usize = 8000;
+const PROGRESS_INTERVAL_MS: u64 = 100;
+
+const TAGGING_PROPERTIES: [&'static str; 9] = [
+    "definition",
+    "reference",
+    "local-definition",
+    "local-reference",
+    "local-scope",
+    "module",
+    "implementation",
+    "documentation",
+    "injection-language",
+];
 
 pub struct DirCrawler {
     store: Store,
     language_registry: Arc<Mutex<LanguageRegistry>>,
+    file_types: FileTypes,
     parser: Parser,
-    languages_by_extension: HashMap<String, (Language, Arc<PropertySheet>)>,
+    languages_by_name: HashMap<String, (String, Language, Arc<PropertySheet>)>,
+    options: CrawlOptions,
+    profile: Option<FileProfile>,
+    tree_cache: Option<HashMap<PathBuf, CachedTree>>,
+}
+
+// The last syntax tree parsed for a file, which can be reused to parse the
+// file's next version incrementally.
+struct CachedTree {
+    language_name: String,
+    source_code: String,
+    tree: Tree,
 }
 
-struct TreeCrawler<'a> {
-    store: StoreFile<'a>,
+// The reason that a file wasn't indexed.
+#[derive(Debug, PartialEq)]
+pub enum SkipReason {
+    ExcludedExtension(String),
+    ExcludedPath,
+    OtherLanguage(String),
+    UnknownLanguage,
+    Unchanged,
+    TooLarge(u64),
+    Generated,
+    Binary,
+    TooManyNodes(usize),
+}
+
+#[derive(Default)]
+pub struct FileProfile {
+    pub parse_time: Duration,
+    pub node_count: usize,
+    pub crawl_time: Duration,
+    pub commit_time: Duration,
+    pub definition_count: usize,
+    pub reference_count: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct CrawlOptions {
+    pub skip_locals: bool,
+    pub locals_max_file_size: Option<usize>,
+    pub language_name: Option<String>,
+    pub excluded_extensions: Vec<String>,
+    pub excluded_paths: Option<GlobSet>,
+    pub skip_unchanged: bool,
+    pub abi_version_range: Option<(usize, usize)>,
+    pub ignore_abi_version_range: bool,
+    pub disable_git_ignore: bool,
+    pub disable_git_global: bool,
+    pub disable_ignore: bool,
+    pub max_node_count: Option<usize>,
+    pub max_file_size: Option<u64>,
+    pub index_docs: bool,
+    pub store_source_lines: bool,
+    pub case_insensitive_languages: Vec<String>,
+    pub file_module_languages: Vec<String>,
+    pub languages_without_references: Vec<String>,
+    pub ignored_contexts: HashMap<String, Vec<String>>,
+    pub generated_markers: Option<RegexSet>,
+    pub jobs: Option<usize>,
+    pub follow_symlinks: bool,
+    // Whether files reached through symlinks are recorded under their
+    // canonical paths. This is off when `--no-canonicalize` is passed.
+    pub canonicalize_paths: bool,
+    pub show_progress: bool,
+}
+
+// Reports how many of a crawl's files have been visited, overwriting the
+// same line of stderr at most ten times per second.
+struct Progress {
+    total_count: usize,
+    done_count: AtomicUsize,
+    last_report_time: Mutex<Option<Instant>>,
+}
+
+struct TreeCrawler<'a, 's> {
+    store: StoreFile<'s>,
     scope_stack: Vec<Scope<'a>>,
     module_stack: Vec<Module<'a>>,
+    implementation_stack: Vec<Implementation<'a>>,
+    pending_local_type: Option<&'a str>,
+    last_local_def_scope_index: Option<usize>,
     property_matcher: TreePropertyCursor<'a>,
     source_code: &'a str,
+    options: TreeCrawlerOptions,
+    offset: Point,
+    injections: Vec<Injection<'a>>,
+    ignored_contexts: &'a [String],
+    ignored_context_depth: usize,
+    implicit_module_name: Option<&'a str>,
+}
+
+// A block whose definitions implement an interface, a type, or both.
+struct Implementation<'a> {
+    interface: Option<&'a str>,
+    type_name: Option<&'a str>,
+}
+
+// A region of a file that is written in a different language than the rest
+// of the file, such as a script within an HTML document.
+struct Injection<'a> {
+    language_name: &'a str,
+    start_byte: usize,
+    end_byte: usize,
+    start_position: Point,
+}
+
+#[derive(Clone, Copy)]
+struct TreeCrawlerOptions {
+    record_locals: bool,
+    record_refs: bool,
+    record_docs: bool,
+    record_source_lines: bool,
 }
 
 struct Definition<'a> {
     name: Option<(&'a str, Point)>,
     kind: Option<&'a str>,
+    implemented_interface: Option<&'a str>,
+    receiver_type: Option<&'a str>,
+    visibility: Option<&'a str>,
+    source_line: Option<&'a str>,
     start_position: Point,
     end_position: Point,
 }
@@ -38,9 +172,10 @@ struct Module<'a> {
 }
 
 struct Scope<'a> {
+    id: i64,
     kind: Option<&'a str>,
-    local_refs: Vec<(&'a str, Point)>,
-    local_defs: Vec<(&'a str, Point)>,
+    local_refs: Vec<(&'a str, Point, i64)>,
+    local_defs: Vec<(&'a str, Point, Option<&'a str>)>,
     hoisted_local_defs: HashMap<&'a str, Point>,
 }
 
@@ -49,28 +184,50 @@ pub enum Error {
     IO(io::Error),
     Ignore(ignore::Error),
     SQL(rusqlite::Error),
+    File { path: PathBuf, source: Box<Error> },
+    LanguageVersion { version: usize, min_version: usize, max_version: usize },
+    IncompatibleLanguage(String),
+    Parse,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-impl<'a> TreeCrawler<'a> {
+impl<'a, 's> TreeCrawler<'a, 's> {
     fn new(
-        store: StoreFile<'a>,
+        store: StoreFile<'s>,
         tree: &'a Tree,
         property_sheet: &'a PropertySheet,
         source_code: &'a str,
+        options: TreeCrawlerOptions,
+        offset: Point,
+        ignored_contexts: &'a [String],
     ) -> Self {
         Self {
             store,
             source_code,
+            options,
+            offset,
+            injections: Vec::new(),
+            ignored_contexts,
+            ignored_context_depth: 0,
+            implicit_module_name: None,
             property_matcher: tree.walk_with_properties(property_sheet),
             scope_stack: Vec::new(),
             module_stack: Vec::new(),
+            implementation_stack: Vec::new(),
+            pending_local_type: None,
+            last_local_def_scope_index: None,
         }
     }
 
     fn crawl_tree(&mut self) -> Result<()> {
-        self.push_scope(None);
+        let root = self.property_matcher.node();
+        let start_position = self.position(root.start_position());
+        let end_position = self.position(root.end_position());
+        // When locals aren't recorded, not even the root scope is stored.
+        if self.options.record_locals {
+            self.push_scope(None, start_position, end_position)?;
+        }
         self.push_module();
         let mut visited_node = false;
         loop {
@@ -90,41 +247,105 @@ impl<'a> TreeCrawler<'a> {
             }
         }
         self.pop_module()?;
-        self.pop_scope()?;
+        if self.options.record_locals {
+            self.pop_scope()?;
+        }
         Ok(())
     }
 
     fn enter_node(&mut self) -> Result<()> {
         let node = self.property_matcher.node();
+        let start_position = self.position(node.start_position());
+        let end_position = self.position(node.end_position());
         let mut is_local_def = false;
 
+        // Nothing is recorded within comments, strings, or other kinds of
+        // nodes that are configured to be ignored. Leaf nodes are never left,
+        // so they don't affect the depth.
+        if self.ignored_contexts.iter().any(|kind| kind == node.kind()) {
+            if node.child_count() > 0 {
+                self.ignored_context_depth += 1;
+            }
+            return Ok(());
+        }
+        if self.ignored_context_depth > 0 {
+            return Ok(());
+        }
+
+        if let Some(language_name) = self.get_property("injection-language") {
+            self.injections.push(Injection {
+                language_name,
+                start_byte: node.start_byte() as usize,
+                end_byte: node.end_byte() as usize,
+                start_position,
+            });
+            return Ok(());
+        }
+
         if self.has_property_value("local-definition", "true") {
             is_local_def = true;
-            let scope_type = self.get_property("scope-type");
-            let is_hoisted = self.has_property("local-is-hoisted");
-            if let Some(text) = node.utf8_text(self.source_code).ok() {
-                if is_hoisted {
-                    self.top_scope(scope_type)
-                        .hoisted_local_defs
-                        .insert(text, node.start_position());
-                } else {
-                    self.top_scope(scope_type)
-                        .local_defs
-                        .push((text, node.start_position()));
+            if self.options.record_locals {
+                let scope_type = self.get_property("scope-type");
+                let is_hoisted = self.has_property("local-is-hoisted");
+                if let Some(text) = node.utf8_text(self.source_code).ok() {
+                    if is_hoisted {
+                        self.top_scope(scope_type)
+                            .hoisted_local_defs
+                            .insert(text, start_position);
+                    } else {
+                        let declared_type = self.pending_local_type.take();
+                        let scope_index = self.top_scope_index(scope_type);
+                        self.scope_stack[scope_index]
+                            .local_defs
+                            .push((text, start_position, declared_type));
+                        self.last_local_def_scope_index = Some(scope_index);
+                    }
                 }
             }
         }
 
-        if self.has_property_value("local-reference", "true") && !is_local_def {
+        // A local definition's declared type can either precede its name,
+        // as in `Foo x`, or follow it, as in `let x: Foo`.
+        if self.options.record_locals {
+            match self.get_property("local-declared-type") {
+                Some("before") => {
+                    self.pending_local_type = node.utf8_text(self.source_code).ok();
+                }
+                Some("after") => {
+                    if let Some(text) = node.utf8_text(self.source_code).ok() {
+                        let local_def = self
+                            .last_local_def_scope_index
+                            .and_then(|i| self.scope_stack.get_mut(i))
+                            .and_then(|scope| scope.local_defs.last_mut());
+                        if let Some(local_def) = local_def {
+                            if local_def.2.is_none() {
+                                local_def.2 = Some(text);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if self.options.record_locals
+            && self.options.record_refs
+            && self.has_property_value("local-reference", "true")
+            && !is_local_def
+        {
             if let Some(text) = node.utf8_text(self.source_code).ok() {
-                self.top_scope(self.get_property("scope-type"))
-                    .local_refs
-                    .push((text, node.start_position()));
+                let scope = self.top_scope(self.get_property("scope-type"));
+                let scope_id = scope.id;
+                scope.local_refs.push((text, start_position, scope_id));
             }
         }
 
-        if self.has_property_value("local-scope", "true") {
-            self.push_scope(self.get_property("scope-type"));
+        if self.options.record_locals && self.has_property_value("local-scope", "true") {
+            self.push_scope(
+                self.get_property("scope-type"),
+                start_position,
+                end_position,
+            )?;
         }
 
         if self.has_property_value("module", "true") {
@@ -141,13 +362,52 @@ impl<'a> TreeCrawler<'a> {
             _ => {}
         }
 
+        if self.has_property_value("implementation", "true") {
+            self.implementation_stack.push(Implementation {
+                interface: None,
+                type_name: None,
+            });
+        }
+
+        match self.get_property("implementation-part") {
+            Some("interface") => {
+                if let Some(text) = node.utf8_text(self.source_code).ok() {
+                    if let Some(implementation) = self.implementation_stack.last_mut() {
+                        if implementation.interface.is_none() {
+                            implementation.interface = Some(text);
+                        }
+                    }
+                }
+            }
+            Some("type") => {
+                if let Some(text) = node.utf8_text(self.source_code).ok() {
+                    if let Some(implementation) = self.implementation_stack.last_mut() {
+                        if implementation.type_name.is_none() {
+                            implementation.type_name = Some(text);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
         if self.has_property_value("definition", "true") {
             let kind = self.get_property("definition-type");
+            let visibility = self.get_property("definition-visibility");
+            let source_line = if self.options.record_source_lines {
+                Some(self.source_line(node.start_byte() as usize))
+            } else {
+                None
+            };
             self.top_module().pending_definition_stack.push(Definition {
                 name: None,
                 kind,
-                start_position: node.start_position(),
-                end_position: node.end_position(),
+                implemented_interface: None,
+                receiver_type: None,
+                visibility,
+                source_line,
+                start_position,
+                end_position,
             });
         }
 
@@ -156,7 +416,15 @@ impl<'a> TreeCrawler<'a> {
                 if let Some(text) = node.utf8_text(self.source_code).ok() {
                     let def = self.top_definition().unwrap();
                     if def.name.is_none() {
-                        def.name = Some((text, node.start_position()));
+                        def.name = Some((text, start_position));
+                    }
+                }
+            }
+            Some("receiver-type") => {
+                if let Some(text) = node.utf8_text(self.source_code).ok() {
+                    let def = self.top_definition().unwrap();
+                    if def.receiver_type.is_none() {
+                        def.receiver_type = Some(text);
                     }
                 }
             }
@@ -169,21 +437,39 @@ impl<'a> TreeCrawler<'a> {
             _ => {}
         }
 
-        if self.has_property_value("reference", "true") && !is_local_def {
+        if self.options.record_refs
+            && self.has_property_value("reference", "true")
+            && !is_local_def
+        {
             if let Some(text) = node.utf8_text(self.source_code).ok() {
                 self.store.insert_ref(
                     text,
-                    node.start_position(),
+                    start_position,
                     self.get_property("reference-type"),
                 )?;
             }
         }
 
+        if self.options.record_docs && self.has_property_value("documentation", "true") {
+            if let Some(text) = node.utf8_text(self.source_code).ok() {
+                self.store
+                    .insert_doc(text, start_position, end_position)?;
+            }
+        }
+
         Ok(())
     }
 
     fn leave_node(&mut self) -> Result<()> {
-        if self.has_property("local-scope") {
+        if self.ignored_context_depth > 0 {
+            let node = self.property_matcher.node();
+            if self.ignored_contexts.iter().any(|kind| kind == node.kind()) {
+                self.ignored_context_depth -= 1;
+            }
+            return Ok(());
+        }
+
+        if self.options.record_locals && self.has_property("local-scope") {
             self.pop_scope()?;
         }
 
@@ -195,17 +481,26 @@ impl<'a> TreeCrawler<'a> {
             self.pop_module()?;
         }
 
+        if self.has_property("implementation") {
+            self.implementation_stack.pop();
+        }
+
         Ok(())
     }
 
     fn top_scope(&mut self, kind: Option<&'a str>) -> &mut Scope<'a> {
+        let index = self.top_scope_index(kind);
+        &mut self.scope_stack[index]
+    }
+
+    fn top_scope_index(&self, kind: Option<&'a str>) -> usize {
         self.scope_stack
-            .iter_mut()
+            .iter()
             .enumerate()
             .rev()
             .find_map(|(i, scope)| {
                 if i == 0 || kind.map_or(true, |kind| Some(kind) == scope.kind) {
-                    Some(scope)
+                    Some(i)
                 } else {
                     None
                 }
@@ -220,26 +515,47 @@ impl<'a> TreeCrawler<'a> {
         self.top_module().pending_definition_stack.last_mut()
     }
 
-    fn push_scope(&mut self, kind: Option<&'a str>) {
+    fn push_scope(
+        &mut self,
+        kind: Option<&'a str>,
+        start_position: Point,
+        end_position: Point,
+    ) -> Result<()> {
+        let parent_id = self.scope_stack.last().map(|scope| scope.id);
+        let id = self
+            .store
+            .insert_scope(parent_id, kind, start_position, end_position)?;
         self.scope_stack.push(Scope {
+            id,
             kind,
             local_refs: Vec::new(),
             local_defs: Vec::new(),
             hoisted_local_defs: HashMap::new(),
         });
+        Ok(())
     }
 
     fn pop_scope(&mut self) -> Result<()> {
         let mut scope = self.scope_stack.pop().unwrap();
+        self.last_local_def_scope_index = None;
 
         let mut local_def_ids = Vec::with_capacity(scope.local_defs.len());
-        for (name, position) in scope.local_defs.iter() {
-            local_def_ids.push(self.store.insert_local_def(name, *position)?);
+        for (name, position, declared_type) in scope.local_defs.iter() {
+            local_def_ids.push(self.store.insert_local_def(
+                scope.id,
+                name,
+                *position,
+                false,
+                *declared_type,
+            )?);
         }
 
         let mut hoisted_local_def_ids = HashMap::new();
         for (name, position) in scope.hoisted_local_defs.iter() {
-            hoisted_local_def_ids.insert(name, self.store.insert_local_def(name, *position)?);
+            hoisted_local_def_ids.insert(
+                name,
+                self.store.insert_local_def(scope.id, name, *position, true, None)?,
+            );
         }
 
         let mut parent_scope = self.scope_stack.pop();
@@ -261,7 +577,7 @@ impl<'a> TreeCrawler<'a> {
 
             if let Some(local_def_id) = local_def_id {
                 self.store
-                    .insert_local_ref(local_def_id, local_ref.0, local_ref.1)?;
+                    .insert_local_ref(local_ref.2, local_def_id, local_ref.0, local_ref.1)?;
             } else if let Some(parent_scope) = parent_scope.as_mut() {
                 parent_scope.local_refs.push(local_ref);
             }
@@ -279,31 +595,69 @@ impl<'a> TreeCrawler<'a> {
         });
     }
 
+    // In languages where each file is a module, the file's top-level module is
+    // named after the file unless the grammar finds an explicit name for it.
+    // Unnamed definitions at the top level, like anonymous default exports,
+    // are also given the module's name.
     fn pop_module(&mut self) -> Result<()> {
+        if self.module_stack.len() == 1 {
+            let implicit_module_name = self.implicit_module_name;
+            let module = self.top_module();
+            if module.name.is_none() {
+                module.name = implicit_module_name;
+            }
+        }
         let mod_path = self
             .module_stack
             .iter()
             .filter_map(|m| m.name)
             .collect::<Vec<_>>();
+        let is_file_module = self.module_stack.len() == 1 && self.implicit_module_name.is_some();
         let module = self.module_stack.pop().unwrap();
         for definition in module.definitions {
-            if let Some((name, name_position)) = definition.name {
-                self.store.insert_def(
+            let name = match (definition.name, module.name) {
+                (None, Some(module_name)) if is_file_module => {
+                    Some((module_name, definition.start_position))
+                }
+                (name, _) => name,
+            };
+            if let Some((name, name_position)) = name {
+                let record = DefinitionRecord {
                     name,
                     name_position,
-                    definition.start_position,
-                    definition.end_position,
-                    definition.kind,
-                    &mod_path,
-                )?;
+                    start_position: definition.start_position,
+                    end_position: definition.end_position,
+                    kind: definition.kind,
+                    receiver_type: definition.receiver_type,
+                    visibility: definition.visibility,
+                    source_line: definition.source_line,
+                };
+                self.store.insert_def(&record, &mod_path)?;
+                if let Some(interface) = definition.implemented_interface {
+                    self.store
+                        .insert_implementation(name, name_position, interface)?;
+                }
             }
         }
         Ok(())
     }
 
+    // A definition implements the interface of the innermost implementation
+    // that contains it. A node can be both a definition and an implementation,
+    // as with a class that declares the interfaces that it implements. Unless
+    // the definition specifies its own receiver type, it is taken to be the
+    // type of the implementation.
     fn pop_definition(&mut self) -> Result<()> {
+        let (interface, type_name) = self
+            .implementation_stack
+            .last()
+            .map_or((None, None), |i| (i.interface, i.type_name));
         let module = self.module_stack.last_mut().unwrap();
-        let definition = module.pending_definition_stack.pop().unwrap();
+        let mut definition = module.pending_definition_stack.pop().unwrap();
+        definition.implemented_interface = interface;
+        if definition.receiver_type.is_none() {
+            definition.receiver_type = type_name;
+        }
         module.definitions.push(definition);
         Ok(())
     }
@@ -322,15 +676,45 @@ impl<'a> TreeCrawler<'a> {
     fn has_property_value(&self, prop: &'static str, value: &'static str) -> bool {
         self.get_property(prop) == Some(value)
     }
+
+    // The whole line is stored, so that ctags search patterns can be
+    // anchored to its start and end.
+    fn source_line(&self, byte: usize) -> &'a str {
+        let line_start = self.source_code[..byte].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.source_code[byte..]
+            .find('\n')
+            .map_or(self.source_code.len(), |i| byte + i);
+        self.source_code[line_start..line_end].trim_end_matches('\r')
+    }
+
+    // When crawling an injected region, positions are reported relative to
+    // the start of the region, and must be translated back into the file.
+    fn position(&self, point: Point) -> Point {
+        if point.row == 0 {
+            Point {
+                row: self.offset.row,
+                column: self.offset.column + point.column,
+            }
+        } else {
+            Point {
+                row: self.offset.row + point.row,
+                column: point.column,
+            }
+        }
+    }
 }
 
 impl DirCrawler {
-    pub fn new(store: Store, language_registry: LanguageRegistry) -> Self {
+    pub fn new(store: Store, language_registry: LanguageRegistry, options: CrawlOptions) -> Self {
         Self {
             store: store,
+            file_types: language_registry.file_types(),
             language_registry: Arc::new(Mutex::new(language_registry)),
             parser: Parser::new(),
-            languages_by_extension: HashMap::new(),
+            languages_by_name: HashMap::new(),
+            options,
+            profile: None,
+            tree_cache: None,
         }
     }
 
@@ -338,16 +722,48 @@ impl DirCrawler {
         Ok(Self {
             store: self.store.clone()?,
             language_registry: self.language_registry.clone(),
+            file_types: self.file_types.clone(),
             parser: Parser::new(),
-            languages_by_extension: self.languages_by_extension.clone(),
+            languages_by_name: self.languages_by_name.clone(),
+            options: self.options.clone(),
+            profile: None,
+            tree_cache: None,
         })
     }
 
+    // Keep the syntax tree of each file that is crawled, so that the file
+    // can be reparsed incrementally when it changes. This is only worthwhile
+    // for a long-running process that crawls the same files repeatedly.
+    pub fn enable_tree_cache(&mut self) {
+        self.tree_cache = Some(HashMap::new());
+    }
+
+    fn edited_cached_tree(
+        &mut self,
+        path: &Path,
+        language_name: &str,
+        source_code: &str,
+    ) -> Option<Tree> {
+        let cached_tree = self.tree_cache.as_mut()?.remove(path)?;
+        if cached_tree.language_name != language_name {
+            return None;
+        }
+        let mut tree = cached_tree.tree;
+        tree.edit(&source_edit(&cached_tree.source_code, source_code));
+        Some(tree)
+    }
+
     pub fn crawl_path(&mut self, path: PathBuf) -> Result<()> {
         let last_error = Arc::new(Mutex::new(Ok(())));
+        let progress = if self.options.show_progress {
+            Some(Arc::new(Progress::new(self.walked_file_count(&path))))
+        } else {
+            None
+        };
 
-        WalkBuilder::new(path).build_parallel().run(|| {
+        self.walk_builder(&path).build_parallel().run(|| {
             let last_error = last_error.clone();
+            let progress = progress.clone();
             match self.clone() {
                 Ok(mut crawler) => Box::new({
                     move |entry| {
@@ -355,13 +771,31 @@ impl DirCrawler {
                             Ok(entry) => {
                                 if let Some(t) = entry.file_type() {
                                     if t.is_file() {
-                                        if let Err(e) = crawler.crawl_file(entry.path()) {
-                                            *last_error.lock().unwrap() = Err(e);
-                                            return WalkState::Quit;
+                                        let result = crawler.crawl_walked_file(entry.path());
+                                        if let Some(progress) = progress.as_ref() {
+                                            progress.increment();
+                                        }
+                                        match result {
+                                            Ok(()) => {}
+                                            Err(ref e) if e.is_file_specific() => {
+                                                eprintln!(
+                                                    "Skipping {}: {}",
+                                                    entry.path().display(),
+                                                    e
+                                                );
+                                            }
+                                            Err(e) => {
+                                                *last_error.lock().unwrap() =
+                                                    Err(e.with_path(entry.path()));
+                                                return WalkState::Quit;
+                                            }
                                         }
                                     }
                                 }
                             }
+                            Err(ref e) if is_loop_error(e) => {
+                                eprintln!("Skipping {}", e);
+                            }
                             Err(e) => {
                                 *last_error.lock().unwrap() = Err(e.into());
                             }
@@ -376,47 +810,776 @@ impl DirCrawler {
             }
         });
 
-        Arc::try_unwrap(last_error).unwrap().into_inner().unwrap()
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        Arc::try_unwrap(last_error).unwrap().into_inner().unwrap()?;
+        if !self.store.checkpoint()? {
+            eprintln!("The index's WAL file couldn't be truncated because it is in use");
+        }
+        Ok(())
+    }
+
+    // Counts the files that crawling the path would visit, so that progress
+    // can be reported as a fraction of them.
+    fn walked_file_count(&self, path: &Path) -> usize {
+        self.walk_builder(path)
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map_or(false, |t| t.is_file()))
+            .count()
+    }
+
+    fn is_excluded_path(&self, path: &Path) -> bool {
+        self.options
+            .excluded_paths
+            .as_ref()
+            .map_or(false, |excluded_paths| excluded_paths.is_match(path))
+    }
+
+    fn walk_builder(&self, path: &Path) -> WalkBuilder {
+        let mut walk_builder = WalkBuilder::new(path);
+        walk_builder
+            .git_ignore(!self.options.disable_git_ignore)
+            .git_global(!self.options.disable_git_global)
+            .ignore(!self.options.disable_ignore)
+            .follow_links(self.options.follow_symlinks);
+        if let Some(jobs) = self.options.jobs {
+            walk_builder.threads(jobs);
+        }
+        walk_builder
+    }
+
+    // Check whether crawling the file's directory would visit the file, or
+    // whether it would be skipped because of an ignore rule.
+    fn is_walked(&self, path: &Path) -> Result<bool> {
+        if let Some(dir_path) = path.parent() {
+            for entry in self.walk_builder(dir_path).max_depth(Some(1)).build() {
+                if entry?.path() == path {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    // Bring the index up to date with a file or directory that has changed
+    // on disk. If it no longer exists, it is removed from the index. A
+    // directory that was created or moved is crawled, subject to the same
+    // ignore rules as it would be when crawling its parent.
+    pub fn update_path(&mut self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            self.store.delete_files(path)?;
+        } else if self.is_walked(path)? {
+            if path.is_dir() {
+                self.crawl_path(path.to_owned())?;
+            } else if path.is_file() {
+                self.crawl_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Describe what would happen to the given file if its directory were
+    // crawled, going through the same checks as `crawl_file`.
+    pub fn explain(&mut self, path: &Path) -> Result<String> {
+        if !path.is_file() {
+            return Ok("it is not a file".to_owned());
+        }
+
+        if !self.is_walked(path)? {
+            return Ok("it is hidden or matches an ignore rule".to_owned());
+        }
+
+        let extension = path.extension().and_then(|e| e.to_str());
+        if let Some(extension) = extension {
+            if self.options.excluded_extensions.iter().any(|e| e == extension) {
+                return Ok(SkipReason::ExcludedExtension(extension.to_owned()).to_string());
+            }
+        }
+
+        if self.is_excluded_path(path) {
+            return Ok(SkipReason::ExcludedPath.to_string());
+        }
+
+        let language_name = self
+            .file_types
+            .language_name_for_path(path)
+            .map(|name| name.to_owned());
+        let language_name = match (language_name, extension) {
+            (Some(language_name), _) => language_name,
+            (None, Some(extension)) => {
+                return Ok(format!("no grammar handles the extension .{}", extension))
+            }
+            (None, None) => return Ok("it has no extension".to_owned()),
+        };
+
+        if let Some(expected_language_name) = self.options.language_name.as_ref() {
+            if *expected_language_name != language_name {
+                return Ok(SkipReason::OtherLanguage(expected_language_name.clone()).to_string());
+            }
+        }
+
+        let language = match self.language_for_path(path) {
+            Ok(Some(language)) => language,
+            Ok(None) => return Ok(format!("the {} grammar could not be found", language_name)),
+            Err(e) => return Ok(format!("the {} grammar failed to load: {}", language_name, e)),
+        };
+
+        if !self.options.ignore_abi_version_range {
+            if let Some(error) = self.abi_version_error(language.1) {
+                return Ok(error.to_string());
+            }
+        }
+
+        if let Some(max_file_size) = self.options.max_file_size {
+            if fs::metadata(path)?.len() > max_file_size {
+                return Ok(SkipReason::TooLarge(max_file_size).to_string());
+            }
+        }
+
+        let bytes = fs::read(path)?;
+        if is_binary(&bytes) {
+            return Ok(SkipReason::Binary.to_string());
+        }
+        let is_valid_utf8 = str::from_utf8(&bytes).is_ok();
+        let source_code = decode_source(bytes);
+
+        if let Some(generated_markers) = self.options.generated_markers.as_ref() {
+            let is_generated = source_code
+                .lines()
+                .take(GENERATED_MARKER_LINE_COUNT)
+                .any(|line| generated_markers.is_match(line));
+            if is_generated {
+                return Ok(SkipReason::Generated.to_string());
+            }
+        }
+
+        if let Some(max_node_count) = self.options.max_node_count {
+            self.parser
+                .set_language(language.1)
+                .map_err(Error::IncompatibleLanguage)?;
+            let tree = self
+                .parser
+                .parse_str(source_code.trim_start_matches(BYTE_ORDER_MARK), None)
+                .ok_or(Error::Parse)?;
+            if has_more_nodes_than(&tree, max_node_count) {
+                return Ok(SkipReason::TooManyNodes(max_node_count).to_string());
+            }
+        }
+
+        if is_valid_utf8 {
+            Ok(format!("it would be indexed as {}", language_name))
+        } else {
+            Ok(format!(
+                "it would be indexed as {}, but it is not valid UTF-8, so its invalid bytes \
+                 would be replaced with `?`",
+                language_name
+            ))
+        }
     }
 
-    fn crawl_file(&mut self, path: &Path) -> Result<()> {
-        let mut file = File::open(path)?;
+    // When symlinks are followed, the same file can be reached through
+    // several paths, so files are recorded under their canonical paths,
+    // unless paths shouldn't be canonicalized.
+    fn crawl_walked_file(&mut self, path: &Path) -> Result<()> {
+        if self.options.follow_symlinks && self.options.canonicalize_paths {
+            self.crawl_file(&fs::canonicalize(path)?)?;
+        } else {
+            self.crawl_file(path)?;
+        }
+        Ok(())
+    }
+
+    // Returns the reason that the file was skipped, if it wasn't indexed.
+    fn crawl_file(&mut self, path: &Path) -> Result<Option<SkipReason>> {
         if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
-            let language;
-            let property_sheet;
-
-            if let Some((l, p)) = self.languages_by_extension.get(extension) {
-                language = *l;
-                property_sheet = p.clone();
-            } else if let Some((l, p)) = self
-                .language_registry
-                .lock()
-                .unwrap()
-                .language_for_file_extension(extension)?
+            if self.options.excluded_extensions.iter().any(|e| e == extension) {
+                return Ok(Some(SkipReason::ExcludedExtension(extension.to_owned())));
+            }
+        }
+
+        if self.is_excluded_path(path) {
+            return Ok(Some(SkipReason::ExcludedPath));
+        }
+
+        if let Some(language_name) = self.options.language_name.as_ref() {
+            if self.file_types.language_name_for_path(path) != Some(language_name.as_str()) {
+                return Ok(Some(SkipReason::OtherLanguage(language_name.clone())));
+            }
+        }
+
+        if let Some(language) = self.language_for_path(path)? {
+            let mtime = modified_time(path);
+            if self.options.skip_unchanged && mtime.is_some() {
+                if self.store.file_modified_time(path)? == mtime {
+                    return Ok(Some(SkipReason::Unchanged));
+                }
+            }
+
+            if let Some(max_file_size) = self.options.max_file_size {
+                if fs::metadata(path)?.len() > max_file_size {
+                    eprintln!("Skipping {}: larger than {} bytes", path.display(), max_file_size);
+                    return Ok(Some(SkipReason::TooLarge(max_file_size)));
+                }
+            }
+
+            let source_code = read_source_file(path)?;
+            if self.options.skip_unchanged
+                && self.store.file_content_hash(path)? == Some(content_hash(&source_code))
             {
-                self.languages_by_extension.insert(extension.to_owned(), (l, p.clone()));
-                language = l;
-                property_sheet = p;
-            } else {
-                return Ok(());
+                // The file was touched without being changed, as happens when
+                // switching branches. Record its new mtime so that it can be
+                // skipped without being read next time.
+                if let Some(mtime) = mtime {
+                    self.store.set_file_modified_time(path, mtime)?;
+                }
+                return Ok(Some(SkipReason::Unchanged));
+            }
+
+            if is_binary(source_code.as_bytes()) {
+                return Ok(Some(SkipReason::Binary));
+            }
+
+            if let Some(generated_markers) = self.options.generated_markers.as_ref() {
+                let is_generated = source_code
+                    .lines()
+                    .take(GENERATED_MARKER_LINE_COUNT)
+                    .any(|line| generated_markers.is_match(line));
+                if is_generated {
+                    eprintln!("Skipping {}: generated file", path.display());
+                    return Ok(Some(SkipReason::Generated));
+                }
+            }
+            self.crawl_source(path, language, &source_code, mtime)
+        } else {
+            Ok(Some(SkipReason::UnknownLanguage))
+        }
+    }
+
+    // Index some source code that doesn't come from the file system. The
+    // language is chosen based on `path`, but the definitions and references
+    // are recorded under `indexed_path`.
+    pub fn crawl_buffer(
+        &mut self,
+        path: &Path,
+        indexed_path: &Path,
+        source_code: &str,
+    ) -> Result<()> {
+        match self.language_for_path(path)? {
+            Some(language) => {
+                self.crawl_source(indexed_path, language, source_code, None)?;
+                Ok(())
+            }
+            None => {
+                eprintln!("No language found for {}", path.display());
+                Ok(())
             }
+        }
+    }
+
+    pub fn crawl_buffer_with_language(
+        &mut self,
+        language_name: &str,
+        indexed_path: &Path,
+        source_code: &str,
+    ) -> Result<()> {
+        match self.language_for_name(language_name)? {
+            Some(language) => {
+                self.crawl_source(indexed_path, language, source_code, None)?;
+                Ok(())
+            }
+            None => {
+                eprintln!("No language named {}", language_name);
+                Ok(())
+            }
+        }
+    }
+
+    fn crawl_source(
+        &mut self,
+        path: &Path,
+        (language_name, language, property_sheet): (String, Language, Arc<PropertySheet>),
+        source_code: &str,
+        mtime: Option<i64>,
+    ) -> Result<Option<SkipReason>> {
+        if !self.options.ignore_abi_version_range {
+            if let Some(error) = self.abi_version_error(language) {
+                return Err(error);
+            }
+        }
+
+        self.parser
+            .set_language(language)
+            .map_err(Error::IncompatibleLanguage)?;
+
+        let content_hash = content_hash(source_code);
+
+        // Editors don't count a byte order mark when reporting columns
+        // on the first line, so leave it out of the parsed text.
+        let source_code = source_code.trim_start_matches(BYTE_ORDER_MARK);
+
+        let parse_start = Instant::now();
+        let old_tree = self.edited_cached_tree(path, &language_name, source_code);
+        let tree = self
+            .parser
+            .parse_str(source_code, old_tree.as_ref())
+            .ok_or(Error::Parse)?;
+        if let Some(tree_cache) = self.tree_cache.as_mut() {
+            tree_cache.insert(
+                path.to_owned(),
+                CachedTree {
+                    language_name: language_name.clone(),
+                    source_code: source_code.to_owned(),
+                    tree: tree.clone(),
+                },
+            );
+        }
+        if let Some(profile) = self.profile.as_mut() {
+            profile.parse_time = parse_start.elapsed();
+            profile.node_count = count_nodes(&tree, usize::max_value());
+        }
+        if let Some(max_node_count) = self.options.max_node_count {
+            if has_more_nodes_than(&tree, max_node_count) {
+                eprintln!(
+                    "Skipping {}: more than {} syntax nodes",
+                    path.display(),
+                    max_node_count
+                );
+                return Ok(Some(SkipReason::TooManyNodes(max_node_count)));
+            }
+        }
+        let tree_crawler_options = TreeCrawlerOptions {
+            record_locals: !self.options.skip_locals
+                && self
+                    .options
+                    .locals_max_file_size
+                    .map_or(true, |max_size| source_code.len() <= max_size),
+            record_refs: !self
+                .options
+                .languages_without_references
+                .contains(&language_name),
+            record_docs: self.options.index_docs,
+            record_source_lines: self.options.store_source_lines,
+        };
+        let fold_names = self
+            .options
+            .case_insensitive_languages
+            .contains(&language_name);
+        let ignored_contexts = self
+            .options
+            .ignored_contexts
+            .get(&language_name)
+            .map_or(&[][..], |kinds| &kinds[..]);
+        let crawl_start = Instant::now();
+        let store = self.store.file(path, &language_name, fold_names)?;
+        let mut crawler = TreeCrawler::new(
+            store,
+            &tree,
+            &property_sheet,
+            source_code,
+            tree_crawler_options,
+            Point { row: 0, column: 0 },
+            ignored_contexts,
+        );
+        if self.options.file_module_languages.contains(&language_name) {
+            crawler.implicit_module_name = path.file_stem().and_then(|stem| stem.to_str());
+        }
+        crawler.crawl_tree()?;
 
+        // Crawl each injected region with its own language. Regions that are
+        // nested within other injected regions are not crawled.
+        let mut store = crawler.store;
+        for injection in crawler.injections {
+            let name = injection.language_name;
+            let (language, property_sheet) = match self.languages_by_name.get(name) {
+                Some((_, language, property_sheet)) => (*language, property_sheet.clone()),
+                None => match self.language_registry.lock().unwrap().language_for_name(name)? {
+                    Some((language, property_sheet)) => {
+                        self.languages_by_name.insert(
+                            name.to_owned(),
+                            (name.to_owned(), language, property_sheet.clone()),
+                        );
+                        (language, property_sheet)
+                    }
+                    None => continue,
+                },
+            };
+            let injected_source_code = &source_code[injection.start_byte..injection.end_byte];
             self.parser
                 .set_language(language)
-                .expect("Incompatible language version");
-            let mut source_code = String::new();
-            file.read_to_string(&mut source_code)?;
-            let tree = self
+                .map_err(Error::IncompatibleLanguage)?;
+            let injected_tree = self
                 .parser
-                .parse_str(&source_code, None)
-                .expect("Parsing failed");
-            let store = self.store.file(path)?;
-            let mut crawler = TreeCrawler::new(store, &tree, &property_sheet, &source_code);
-            crawler.crawl_tree()?;
-            crawler.store.commit()?;
+                .parse_str(injected_source_code, None)
+                .ok_or(Error::Parse)?;
+            let mut injected_crawler = TreeCrawler::new(
+                store,
+                &injected_tree,
+                &property_sheet,
+                injected_source_code,
+                tree_crawler_options,
+                injection.start_position,
+                &[],
+            );
+            injected_crawler.crawl_tree()?;
+            store = injected_crawler.store;
+        }
+
+        if let Some(mtime) = mtime {
+            store.set_modified_time(mtime)?;
         }
+        store.set_content_hash(content_hash)?;
+        store.set_error_count(count_error_nodes(&tree))?;
+        let commit_start = Instant::now();
+        store.commit()?;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.crawl_time = commit_start - crawl_start;
+            profile.commit_time = commit_start.elapsed();
+        }
+        Ok(None)
+    }
+
+    // Index a single file, measuring how long each step takes. If the file
+    // was skipped, the reason is returned instead.
+    pub fn profile_file(
+        &mut self,
+        path: &Path,
+    ) -> Result<core::result::Result<FileProfile, SkipReason>> {
+        self.profile = Some(FileProfile::default());
+        let result = self.crawl_file(path);
+        let mut profile = self.profile.take().unwrap_or_default();
+        if let Some(skip_reason) = result? {
+            return Ok(Err(skip_reason));
+        }
+        let (definition_count, reference_count) = self.store.symbol_counts(path)?;
+        profile.definition_count = definition_count;
+        profile.reference_count = reference_count;
+        Ok(Ok(profile))
+    }
+
+    // Parse a sample file with the given grammar and report how many nodes
+    // received each of the properties that affect tagging. Returns false if
+    // the property sheet didn't produce any definitions or references.
+    pub fn validate_grammar(&mut self, language_name: &str, sample_path: &Path) -> Result<bool> {
+        let language = self
+            .language_registry
+            .lock()
+            .unwrap()
+            .language_for_name(language_name)?;
+        let (language, property_sheet) = match language {
+            Some(language) => language,
+            None => {
+                eprintln!("Unknown language: {}", language_name);
+                return Ok(false);
+            }
+        };
+
+        self.parser
+            .set_language(language)
+            .map_err(Error::IncompatibleLanguage)?;
+        let source_code = read_source_file(sample_path)?;
+        let source_code = source_code.trim_start_matches(BYTE_ORDER_MARK);
+        let tree = self
+            .parser
+            .parse_str(source_code, None)
+            .ok_or(Error::Parse)?;
+
+        let mut counts = BTreeMap::new();
+        let mut cursor = tree.walk_with_properties(&property_sheet);
+        let mut visited_node = false;
+        loop {
+            if !visited_node {
+                for (key, _) in cursor.node_properties().iter() {
+                    *counts.entry(key.as_str()).or_insert(0) += 1;
+                }
+            }
+
+            if visited_node {
+                if cursor.goto_next_sibling() {
+                    visited_node = false;
+                } else if !cursor.goto_parent() {
+                    break;
+                }
+            } else if !cursor.goto_first_child() {
+                visited_node = true;
+            }
+        }
+
+        println!("sample: {}", sample_path.display());
+        for property in TAGGING_PROPERTIES.iter() {
+            println!("{:<20} {}", property, counts.get(property).cloned().unwrap_or(0));
+        }
+        for (property, count) in counts.iter() {
+            if !TAGGING_PROPERTIES.contains(property) {
+                println!("{:<20} {}", property, count);
+            }
+        }
+
+        let produces_tags = counts.contains_key("definition") || counts.contains_key("reference");
+        if !produces_tags {
+            println!("warning: the property sheet produced no definitions or references");
+        }
+        Ok(produces_tags)
+    }
+
+    pub fn debug_file(&mut self, path: &Path, all_nodes: bool) -> Result<()> {
+        let (_, language, property_sheet) = match self.language_for_path(path)? {
+            Some(language) => language,
+            None => {
+                eprintln!("No language found for {}", path.display());
+                return Ok(());
+            }
+        };
+
+        self.parser
+            .set_language(language)
+            .map_err(Error::IncompatibleLanguage)?;
+        let source_code = read_source_file(path)?;
+        let source_code = source_code.trim_start_matches(BYTE_ORDER_MARK);
+        let tree = self
+            .parser
+            .parse_str(source_code, None)
+            .ok_or(Error::Parse)?;
+
+        let mut cursor = tree.walk_with_properties(&property_sheet);
+        let mut depth = 0;
+        let mut visited_node = false;
+        loop {
+            if !visited_node {
+                let node = cursor.node();
+                let mut properties = cursor.node_properties().iter().collect::<Vec<_>>();
+                if all_nodes || !properties.is_empty() {
+                    properties.sort();
+                    let start = node.start_position();
+                    let end = node.end_position();
+                    print!(
+                        "{:indent$}{} [{}, {}] - [{}, {}]",
+                        "",
+                        node.kind(),
+                        start.row,
+                        start.column,
+                        end.row,
+                        end.column,
+                        indent = depth * 2
+                    );
+                    for (key, value) in properties {
+                        print!(" {}={}", key, value);
+                    }
+                    println!();
+                }
+            }
+
+            if visited_node {
+                if cursor.goto_next_sibling() {
+                    visited_node = false;
+                } else if cursor.goto_parent() {
+                    depth -= 1;
+                } else {
+                    break;
+                }
+            } else if cursor.goto_first_child() {
+                depth += 1;
+            } else {
+                visited_node = true;
+            }
+        }
+
         Ok(())
     }
+
+    fn language_for_path(
+        &mut self,
+        path: &Path,
+    ) -> Result<Option<(String, Language, Arc<PropertySheet>)>> {
+        let name = match self.file_types.language_name_for_path(path) {
+            Some(name) => name.to_owned(),
+            None => return Ok(None),
+        };
+        self.language_for_name(&name)
+    }
+
+    fn language_for_name(
+        &mut self,
+        name: &str,
+    ) -> Result<Option<(String, Language, Arc<PropertySheet>)>> {
+        if let Some(language) = self.languages_by_name.get(name) {
+            return Ok(Some(language.clone()));
+        }
+
+        let mut language_registry = self.language_registry.lock().unwrap();
+        if let Some((language, property_sheet)) = language_registry.language_for_name(name)? {
+            // Grammars outside the accepted ABI range are still loaded when
+            // the check is overridden, but it's worth mentioning once.
+            if self.options.ignore_abi_version_range {
+                if let Some(error) = self.abi_version_error(language) {
+                    eprintln!("Loading the {} grammar anyway. {}", name, error);
+                }
+            }
+            let result = (name.to_owned(), language, property_sheet);
+            self.languages_by_name.insert(name.to_owned(), result.clone());
+            return Ok(Some(result));
+        }
+        Ok(None)
+    }
+
+    fn abi_version_error(&self, language: Language) -> Option<Error> {
+        let (min_version, max_version) = self.options.abi_version_range?;
+        let version = language.version();
+        if version < min_version || version > max_version {
+            Some(Error::LanguageVersion {
+                version,
+                min_version,
+                max_version,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+pub fn read_source_file(path: &Path) -> io::Result<String> {
+    Ok(decode_source(fs::read(path)?))
+}
+
+// Like git, treat files with a NUL byte near their start as binary files.
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_CHECK_BYTE_COUNT)].contains(&0)
+}
+
+// Files that aren't valid UTF-8 are still indexed on a best-effort basis. Each
+// invalid byte is replaced with a `?`, rather than with a multi-byte
+// replacement character, so that byte offsets and columns still match the
+// file.
+pub fn decode_source(mut bytes: Vec<u8>) -> String {
+    let mut start = 0;
+    while let Err(error) = str::from_utf8(&bytes[start..]) {
+        let invalid_start = start + error.valid_up_to();
+        let invalid_len = error.error_len().unwrap_or(bytes.len() - invalid_start);
+        for byte in bytes[invalid_start..invalid_start + invalid_len].iter_mut() {
+            *byte = b'?';
+        }
+        start = invalid_start + invalid_len;
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+// Describe the change from `old` to `new` as a single edit, spanning from the
+// first byte that differs to the last one.
+fn source_edit(old: &str, new: &str) -> InputEdit {
+    let mut start = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut suffix_len = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(old.len().min(new.len()) - start)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix_len) {
+        suffix_len -= 1;
+    }
+    let old_end = old.len() - suffix_len;
+    let new_end = new.len() - suffix_len;
+    InputEdit {
+        start_byte: start as u32,
+        old_end_byte: old_end as u32,
+        new_end_byte: new_end as u32,
+        start_position: position_at(old, start),
+        old_end_position: position_at(old, old_end),
+        new_end_position: position_at(new, new_end),
+    }
+}
+
+fn position_at(text: &str, offset: usize) -> Point {
+    let text = &text[..offset];
+    Point {
+        row: text.bytes().filter(|b| *b == b'\n').count() as u32,
+        column: text.rfind('\n').map_or(offset, |i| offset - i - 1) as u32,
+    }
+}
+
+fn is_loop_error(error: &ignore::Error) -> bool {
+    match error {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            is_loop_error(err)
+        }
+        _ => false,
+    }
+}
+
+fn content_hash(source_code: &str) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    source_code.hash(&mut hasher);
+    hasher.finish() as i64
+}
+
+// The modification time of a file in nanoseconds since the Unix epoch.
+fn modified_time(path: &Path) -> Option<i64> {
+    let duration = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?;
+    Some(duration.as_secs() as i64 * 1_000_000_000 + duration.subsec_nanos() as i64)
+}
+
+fn has_more_nodes_than(tree: &Tree, max_node_count: usize) -> bool {
+    count_nodes(tree, max_node_count) > max_node_count
+}
+
+// Counts the nodes in a tree, stopping once the count exceeds `max_node_count`.
+fn count_nodes(tree: &Tree, max_node_count: usize) -> usize {
+    let mut cursor = tree.walk();
+    let mut node_count = 1;
+    loop {
+        if !cursor.goto_first_child() && !cursor.goto_next_sibling() {
+            loop {
+                if !cursor.goto_parent() {
+                    return node_count;
+                }
+                if cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+        node_count += 1;
+        if node_count > max_node_count {
+            return node_count;
+        }
+    }
+}
+
+// Counts the ERROR nodes in a tree, along with the MISSING nodes that the
+// parser inserts to recover from errors like unclosed brackets, only
+// descending into nodes that contain errors.
+fn count_error_nodes(tree: &Tree) -> usize {
+    let mut cursor = tree.walk();
+    let mut error_count = 0;
+    loop {
+        let node = cursor.node();
+        if node.kind() == "ERROR" || node.is_missing() {
+            error_count += 1;
+        }
+        if !(node.has_error() && cursor.goto_first_child()) && !cursor.goto_next_sibling() {
+            loop {
+                if !cursor.goto_parent() {
+                    return error_count;
+                }
+                if cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -425,11 +1588,109 @@ impl fmt::Display for Error {
             Error::IO(e) => e.fmt(f),
             Error::SQL(e) => e.fmt(f),
             Error::Ignore(e) => e.fmt(f),
+            Error::File { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::LanguageVersion {
+                version,
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "Language ABI version {} is outside the accepted range {}-{}",
+                version, min_version, max_version
+            ),
+            Error::IncompatibleLanguage(message) => write!(f, "Incompatible language: {}", message),
+            Error::Parse => write!(f, "Parsing failed"),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl Progress {
+    fn new(total_count: usize) -> Self {
+        Self {
+            total_count,
+            done_count: AtomicUsize::new(0),
+            last_report_time: Mutex::new(None),
+        }
+    }
+
+    // Threads that find another thread reporting don't wait for it.
+    fn increment(&self) {
+        let done_count = self.done_count.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Ok(mut last_report_time) = self.last_report_time.try_lock() {
+            let interval = Duration::from_millis(PROGRESS_INTERVAL_MS);
+            let is_due = last_report_time.map_or(true, |time| time.elapsed() >= interval);
+            if is_due {
+                *last_report_time = Some(Instant::now());
+                eprint!("\rIndexed {}/{} files", done_count, self.total_count);
+            }
+        }
+    }
+
+    fn finish(&self) {
+        let done_count = self.done_count.load(Ordering::SeqCst);
+        eprintln!("\rIndexed {}/{} files", done_count, self.total_count);
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::ExcludedExtension(extension) => {
+                write!(f, "files with the extension .{} are excluded", extension)
+            }
+            SkipReason::ExcludedPath => write!(f, "it matches an exclude pattern in the config"),
+            SkipReason::OtherLanguage(language_name) => {
+                write!(f, "it is not a {} file", language_name)
+            }
+            SkipReason::UnknownLanguage => write!(f, "no grammar handles it"),
+            SkipReason::Unchanged => write!(f, "it hasn't changed since it was last indexed"),
+            SkipReason::TooLarge(max_file_size) => {
+                write!(f, "it is larger than {} bytes", max_file_size)
+            }
+            SkipReason::Generated => write!(f, "it contains a generated-code marker"),
+            SkipReason::Binary => write!(f, "it contains a NUL byte, so it may be a binary file"),
+            SkipReason::TooManyNodes(max_node_count) => {
+                write!(f, "it has more than {} syntax nodes", max_node_count)
+            }
+        }
+    }
+}
+
+impl Error {
+    // Whether the error only affects a single file, so that crawling can
+    // continue with the remaining files.
+    pub fn is_file_specific(&self) -> bool {
+        match self {
+            Error::LanguageVersion { .. } | Error::IncompatibleLanguage(_) | Error::Parse => true,
+            Error::File { source, .. } => source.is_file_specific(),
+            _ => false,
+        }
+    }
+
+    pub fn with_path(self, path: &Path) -> Error {
+        match self {
+            Error::File { .. } => self,
+            _ => Error::File {
+                path: path.to_owned(),
+                source: Box::new(self),
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(e) => Some(e),
+            Error::SQL(e) => Some(e),
+            Error::Ignore(e) => Some(e),
+            Error::File { source, .. } => Some(source.as_ref()),
+            Error::LanguageVersion { .. } => None,
+            Error::IncompatibleLanguage(_) => None,
+            Error::Parse => None,
+        }
+    }
+}
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
@@ -448,3 +1709,401 @@ impl From<rusqlite::Error> for Error {
         Error::SQL(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Location;
+    use crate::test_util::{self, TempDir, JAVASCRIPT_PROPERTY_SHEET_JSON};
+
+    #[test]
+    fn test_skip_generated_files() {
+        let dir = TempDir::new();
+        let generated_path = dir.write("src/generated.js", "// @generated\nfunction a() {}\n");
+        let handwritten_path = dir.write("src/handwritten.js", "function b() {}\n");
+        let options = CrawlOptions {
+            generated_markers: Some(RegexSet::new(&["@generated"]).unwrap()),
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+
+        let mut store = test_util::store(&dir);
+        assert_eq!(store.file_paths(dir.path()).unwrap(), vec![handwritten_path.clone()]);
+        assert_eq!(
+            crawler.explain(&generated_path).unwrap(),
+            "it contains a generated-code marker"
+        );
+        assert_eq!(
+            crawler.explain(&handwritten_path).unwrap(),
+            "it would be indexed as javascript"
+        );
+    }
+
+    #[test]
+    fn test_languages_without_references() {
+        let source_code = "function a() {}\nfunction b() { a(); a(); }\n";
+        let dir = TempDir::new();
+        let path = dir.write("src/main.js", source_code);
+        let mut crawler = javascript_crawler(&dir, CrawlOptions::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        assert_eq!(test_util::store(&dir).symbol_counts(&path).unwrap(), (2, 2));
+
+        let dir = TempDir::new();
+        let path = dir.write("src/main.js", source_code);
+        let options = CrawlOptions {
+            languages_without_references: vec!["javascript".to_owned()],
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        assert_eq!(test_util::store(&dir).symbol_counts(&path).unwrap(), (2, 0));
+    }
+
+    #[test]
+    fn test_ignored_contexts() {
+        let dir = TempDir::new();
+        let path = dir.write("src/main.js", "function a() {}\nlet s = `${a()}`;\na();\n");
+        let mut ignored_contexts = HashMap::new();
+        ignored_contexts.insert("javascript".to_owned(), vec!["template_string".to_owned()]);
+        let options = CrawlOptions {
+            ignored_contexts,
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+
+        let mut store = test_util::store(&dir);
+        assert_eq!(store.symbol_counts(&path).unwrap(), (1, 1));
+        let (definitions, _) = store.find_definition(&path, Point::new(2, 0), 10).unwrap();
+        assert_eq!(definitions.len(), 1);
+        assert!(store.find_definition(&path, Point::new(1, 11), 10).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_definitions_and_references_without_kinds() {
+        let property_sheet_json = JAVASCRIPT_PROPERTY_SHEET_JSON
+            .replace(r#", "definition-type": "function""#, "")
+            .replace(r#", "reference-type": "call""#, "");
+        let dir = TempDir::new();
+        let path = dir.write("src/main.js", "function a() {}\na();\n");
+        let registry = test_util::javascript_registry(&dir, Some(&property_sheet_json));
+        let mut crawler = DirCrawler::new(test_util::store(&dir), registry, Default::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+
+        let mut store = test_util::store(&dir);
+        assert_eq!(store.symbol_counts(&path).unwrap(), (1, 1));
+        let (definitions, _) = store.find_definition(&path, Point::new(1, 0), 10).unwrap();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].position, Point::new(0, 9));
+        assert_eq!(definitions[0].kind, None);
+    }
+
+    #[test]
+    fn test_multibyte_identifiers() {
+        let dir = TempDir::new();
+        let path = dir.write("src/main.js", "function café() {}\ncafé();\n");
+        let mut crawler = javascript_crawler(&dir, CrawlOptions::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+
+        // Columns and lengths are both measured in bytes, so the reference's
+        // last byte is at column 4, even though it only has four characters.
+        let mut store = test_util::store(&dir);
+        let (definitions, _) = store.find_definition(&path, Point::new(1, 4), 10).unwrap();
+        assert_eq!(
+            definitions
+                .iter()
+                .map(|definition| (definition.position, definition.length))
+                .collect::<Vec<_>>(),
+            vec![(Point::new(0, 9), 5)]
+        );
+        assert!(store.find_definition(&path, Point::new(1, 5), 10).unwrap().0.is_empty());
+        assert_eq!(
+            store.find_usages(&path, Point::new(0, 13), None).unwrap(),
+            vec![Location {
+                path: path.clone(),
+                position: Point::new(1, 0),
+                length: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_profile_file() {
+        let dir = TempDir::new();
+        let path = dir.write("src/main.js", "function a() {}\na();\n");
+        let large_path = dir.write("src/large.js", &"a();\n".repeat(100));
+        let options = CrawlOptions {
+            max_node_count: Some(50),
+            excluded_extensions: vec!["mjs".to_owned()],
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+
+        let profile = crawler.profile_file(&path).unwrap().ok().unwrap();
+        assert!(profile.node_count > 0);
+        assert_eq!((profile.definition_count, profile.reference_count), (1, 1));
+        assert_eq!(
+            crawler.profile_file(&large_path).unwrap().err(),
+            Some(SkipReason::TooManyNodes(50))
+        );
+        assert_eq!(
+            crawler.profile_file(&dir.write("src/a.mjs", "")).unwrap().err(),
+            Some(SkipReason::ExcludedExtension("mjs".to_owned()))
+        );
+        assert_eq!(
+            crawler.profile_file(&dir.write("src/a.rb", "")).unwrap().err(),
+            Some(SkipReason::UnknownLanguage)
+        );
+    }
+
+    #[test]
+    fn test_file_modules() {
+        // Tags anonymous functions as definitions, as well as declarations.
+        let property_sheet_json = r#"{
+          "states": [
+            {
+              "property_set_id": 0,
+              "default_next_state_id": 0,
+              "transitions": [
+                {"type": "function_declaration", "named": true, "state_id": 1},
+                {"type": "function", "named": true, "state_id": 3}
+              ]
+            },
+            {
+              "property_set_id": 1,
+              "default_next_state_id": 0,
+              "transitions": [{"type": "identifier", "named": true, "state_id": 2}]
+            },
+            {"property_set_id": 2, "default_next_state_id": 0, "transitions": []},
+            {"property_set_id": 1, "default_next_state_id": 0, "transitions": []}
+          ],
+          "property_sets": [
+            {},
+            {"definition": "true", "definition-type": "function"},
+            {"definition-part": "name"}
+          ]
+        }"#;
+        let dir = TempDir::new();
+        let path = dir.write("src/widget.js", "function a() {}\nexport default function () {}\n");
+        let registry = test_util::javascript_registry(&dir, Some(property_sheet_json));
+        let options = CrawlOptions {
+            file_module_languages: vec!["javascript".to_owned()],
+            ..Default::default()
+        };
+        let mut crawler = DirCrawler::new(test_util::store(&dir), registry, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+
+        let mut store = test_util::store(&dir);
+        let symbol = |store: &mut Store, name| {
+            store
+                .find_symbol(name, false, 10)
+                .unwrap()
+                .into_iter()
+                .map(|definition| {
+                    (definition.path, definition.position, definition.module_path.unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            symbol(&mut store, "a"),
+            vec![(path.clone(), Point::new(0, 9), "widget".to_owned())]
+        );
+        assert_eq!(
+            symbol(&mut store, "widget"),
+            vec![(path.clone(), Point::new(1, 15), "widget".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_walked_file_count() {
+        let dir = TempDir::new();
+        dir.write("src/a.js", "");
+        dir.write("src/lib/b.js", "");
+        dir.write("src/lib/c.rb", "");
+        dir.write("src/vendor/d.js", "");
+        dir.write("src/.ignore", "vendor\n");
+        let crawler = javascript_crawler(&dir, CrawlOptions::default());
+        assert_eq!(crawler.walked_file_count(&dir.path().join("src")), 3);
+    }
+
+    #[test]
+    fn test_explain() {
+        let dir = TempDir::new();
+        let mut excluded_paths = globset::GlobSetBuilder::new();
+        excluded_paths.add(globset::Glob::new("**/vendor/**").unwrap());
+        let options = CrawlOptions {
+            excluded_extensions: vec!["mjs".to_owned()],
+            excluded_paths: Some(excluded_paths.build().unwrap()),
+            max_file_size: Some(1000),
+            max_node_count: Some(50),
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        dir.write("src/.ignore", "ignored.js\n");
+        let mut explain = |relative_path: &str, contents: &[u8]| {
+            let path = dir.path().join(relative_path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            crawler.explain(&path).unwrap()
+        };
+
+        assert_eq!(explain("src/a.js", b"a();\n"), "it would be indexed as javascript");
+        assert_eq!(explain("src/ignored.js", b""), "it is hidden or matches an ignore rule");
+        assert_eq!(explain("src/a.mjs", b""), "files with the extension .mjs are excluded");
+        assert_eq!(
+            explain("src/vendor/a.js", b""),
+            "it matches an exclude pattern in the config"
+        );
+        assert_eq!(explain("src/a.rb", b""), "no grammar handles the extension .rb");
+        assert_eq!(explain("src/Makefile", b""), "it has no extension");
+        assert_eq!(explain("src/large.js", &[b' '; 1001]), "it is larger than 1000 bytes");
+        assert_eq!(
+            explain("src/many.js", "a();\n".repeat(100).as_bytes()),
+            "it has more than 50 syntax nodes"
+        );
+        assert_eq!(
+            explain("src/binary.js", b"a();\n\0\x01\x02"),
+            "it contains a NUL byte, so it may be a binary file"
+        );
+        assert_eq!(
+            explain("src/latin1.js", b"a(\"caf\xe9\");\n"),
+            "it would be indexed as javascript, but it is not valid UTF-8, so its invalid \
+             bytes would be replaced with `?`"
+        );
+        assert_eq!(crawler.explain(&dir.path().join("src")).unwrap(), "it is not a file");
+    }
+
+    #[test]
+    fn test_binary_and_invalid_utf8_files() {
+        let dir = TempDir::new();
+        let binary_path = dir.path().join("src/binary.js");
+        let latin1_path = dir.path().join("src/latin1.js");
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(&binary_path, b"function a() {}\n\0\x01\x02").unwrap();
+        fs::write(&latin1_path, b"function b() { return \"caf\xe9\"; }\n").unwrap();
+        let mut crawler = javascript_crawler(&dir, CrawlOptions::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+
+        let mut store = test_util::store(&dir);
+        assert_eq!(store.file_paths(dir.path()).unwrap(), vec![latin1_path.clone()]);
+        assert_eq!(store.symbol_counts(&latin1_path).unwrap(), (1, 0));
+        assert_eq!(decode_source(b"caf\xe9!".to_vec()), "caf?!");
+    }
+
+    #[test]
+    fn test_error_counts() {
+        let dir = TempDir::new();
+        let mut registry = test_util::javascript_registry(&dir, None);
+        let (language, _) = registry.language_for_name("javascript").unwrap().unwrap();
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+
+        let tree = parser.parse_str("function a() { b(); }\n", None).unwrap();
+        assert_eq!(count_error_nodes(&tree), 0);
+
+        // Unclosed brackets are usually recovered from with MISSING nodes,
+        // and stray tokens with ERROR nodes. Either one is counted.
+        for source_code in &["function a() {\n", "a(b, c;\n", "function a() { ) }\n"] {
+            let tree = parser.parse_str(source_code, None).unwrap();
+            assert!(tree.root_node().has_error());
+            assert!(count_error_nodes(&tree) > 0, "no errors counted in {:?}", source_code);
+        }
+
+        let path = dir.write("src/broken.js", "function a() {\nfunction b() { ) }\n");
+        let mut crawler = javascript_crawler(&dir, CrawlOptions::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        let summaries = test_util::store(&dir).file_summaries(Some(&path)).unwrap();
+        assert!(summaries[0].2.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_update_path_with_directories() {
+        let dir = TempDir::new();
+        dir.write("src/main.js", "function a() {}\n");
+        let mut crawler = javascript_crawler(&dir, CrawlOptions::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        let mut store = test_util::store(&dir);
+
+        let lib_path = dir.write("src/lib/util.js", "function b() {}\n");
+        crawler.update_path(&dir.path().join("src/lib")).unwrap();
+        assert_eq!(store.file_paths(&dir.path().join("src/lib")).unwrap(), vec![lib_path]);
+
+        // A renamed directory is reported as both its old and new paths.
+        fs::rename(dir.path().join("src/lib"), dir.path().join("src/util")).unwrap();
+        crawler.update_path(&dir.path().join("src/lib")).unwrap();
+        crawler.update_path(&dir.path().join("src/util")).unwrap();
+        assert!(store.file_paths(&dir.path().join("src/lib")).unwrap().is_empty());
+        assert_eq!(
+            store.file_paths(&dir.path().join("src/util")).unwrap(),
+            vec![dir.path().join("src/util/util.js")]
+        );
+
+        // Hidden directories are skipped, as they are when crawling.
+        dir.write("src/.cache/cached.js", "function c() {}\n");
+        crawler.update_path(&dir.path().join("src/.cache")).unwrap();
+        assert!(store.file_paths(&dir.path().join("src/.cache")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_follow_symlinks() {
+        let dir = TempDir::new();
+        let shared_path = dir.write("shared/util.js", "function util() {}\n");
+        let main_path = dir.write("src/main.js", "util();\n");
+        let linked_path = dir.path().join("src/shared");
+        std::os::unix::fs::symlink(dir.path().join("shared"), &linked_path).unwrap();
+
+        let indexed_paths = |store: &mut Store| {
+            let mut paths = store.file_paths(dir.path()).unwrap();
+            paths.sort();
+            paths
+        };
+
+        let mut crawler = javascript_crawler(&dir, CrawlOptions::default());
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        let mut store = test_util::store(&dir);
+        assert_eq!(indexed_paths(&mut store), vec![main_path.clone()]);
+
+        // Linked files are recorded under their canonical paths.
+        let options = CrawlOptions {
+            follow_symlinks: true,
+            canonicalize_paths: true,
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        assert_eq!(indexed_paths(&mut store), vec![shared_path.clone(), main_path.clone()]);
+
+        // Unless paths shouldn't be canonicalized.
+        store.delete_file(&shared_path).unwrap();
+        let options = CrawlOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        assert_eq!(indexed_paths(&mut store), vec![main_path, linked_path.join("util.js")]);
+    }
+
+    #[test]
+    fn test_follow_symlink_loops() {
+        let dir = TempDir::new();
+        let path = dir.write("src/lib/main.js", "function a() {}\n");
+        std::os::unix::fs::symlink(dir.path().join("src"), dir.path().join("src/lib/loop"))
+            .unwrap();
+
+        let options = CrawlOptions {
+            follow_symlinks: true,
+            canonicalize_paths: true,
+            ..Default::default()
+        };
+        let mut crawler = javascript_crawler(&dir, options);
+        crawler.crawl_path(dir.path().join("src")).unwrap();
+        assert_eq!(test_util::store(&dir).file_paths(dir.path()).unwrap(), vec![path]);
+    }
+
+    fn javascript_crawler(dir: &TempDir, options: CrawlOptions) -> DirCrawler {
+        let registry = test_util::javascript_registry(dir, Some(JAVASCRIPT_PROPERTY_SHEET_JSON));
+        DirCrawler::new(test_util::store(dir), registry, options)
+    }
+}
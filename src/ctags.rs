@@ -0,0 +1,128 @@
+use crate::config::Config;
+use crate::language_registry::LanguageRegistry;
+use crate::store::NamedDefinition;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const DEFAULT_KIND_LETTERS: [(&'static str, &'static str); 14] = [
+    ("class", "c"),
+    ("constant", "d"),
+    ("enum", "g"),
+    ("enum_variant", "e"),
+    ("field", "m"),
+    ("function", "f"),
+    ("interface", "i"),
+    ("macro", "d"),
+    ("method", "m"),
+    ("module", "n"),
+    ("struct", "s"),
+    ("type", "t"),
+    ("union", "u"),
+    ("variable", "v"),
+];
+
+const FALLBACK_KIND_LETTER: &'static str = "x";
+
+pub fn write_tags(
+    output: &mut impl Write,
+    mut definitions: Vec<NamedDefinition>,
+    language_registry: &LanguageRegistry,
+    config: &Config,
+) -> io::Result<()> {
+    definitions.sort_by(|a, b| (&a.name, &a.path, a.position).cmp(&(&b.name, &b.path, b.position)));
+
+    writeln!(output, "!_TAG_FILE_FORMAT\t2\t/extended format/")?;
+    writeln!(output, "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/")?;
+    writeln!(output, "!_TAG_PROGRAM_NAME\ttree-tags\t//")?;
+
+    for definition in definitions {
+        let kind_letters = language_registry
+            .language_name_for_path(&definition.path)
+            .and_then(|language_name| config.language(language_name))
+            .map(|language_config| &language_config.ctags_kinds);
+        let address = match definition.source_line {
+            Some(source_line) => format!("/^{}$/", escape_search_pattern(&source_line)),
+            None => (definition.position.row + 1).to_string(),
+        };
+        writeln!(
+            output,
+            "{}\t{}\t{};\"\t{}",
+            definition.name,
+            definition.path.display(),
+            address,
+            kind_letter(definition.kind.as_ref().map(|k| k.as_str()), kind_letters)
+        )?;
+    }
+
+    Ok(())
+}
+
+fn kind_letter<'a>(
+    kind: Option<&'a str>,
+    kind_letters: Option<&'a HashMap<String, String>>,
+) -> &'a str {
+    if let Some(kind) = kind {
+        if let Some(letter) = kind_letters.and_then(|letters| letters.get(kind)) {
+            return letter;
+        }
+        if let Some((_, letter)) = DEFAULT_KIND_LETTERS.iter().find(|(k, _)| *k == kind) {
+            return letter;
+        }
+    }
+    FALLBACK_KIND_LETTER
+}
+
+// Vim and Emacs read the address as a search pattern, so the delimiter and
+// backslashes must be escaped.
+fn escape_search_pattern(source_line: &str) -> String {
+    let mut result = String::with_capacity(source_line.len());
+    for c in source_line.chars() {
+        if c == '/' || c == '\\' {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tree_sitter::Point;
+
+    #[test]
+    fn test_write_tags_search_patterns() {
+        let registry = LanguageRegistry::new(PathBuf::new(), Vec::new());
+        let definitions = vec![
+            NamedDefinition {
+                name: "parse".to_owned(),
+                module_path: String::new(),
+                kind: Some("function".to_owned()),
+                path: PathBuf::from("src/a.js"),
+                position: Point { row: 4, column: 11 },
+                source_line: Some("  function parse(path = 'a/b\\\\c') {".to_owned()),
+            },
+            NamedDefinition {
+                name: "main".to_owned(),
+                module_path: String::new(),
+                kind: None,
+                path: PathBuf::from("src/b.js"),
+                position: Point { row: 9, column: 0 },
+                source_line: None,
+            },
+        ];
+
+        let mut output = Vec::new();
+        write_tags(&mut output, definitions, &registry, &Config::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().filter(|line| !line.starts_with("!_")).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "main\tsrc/b.js\t10;\"\tx",
+                "parse\tsrc/a.js\t/^  function parse(path = 'a\\/b\\\\\\\\c') {$/;\"\tf",
+            ]
+        );
+    }
+}
@@ -1,17 +1,36 @@
+use globset::{Glob, GlobMatcher};
 use libloading::{Library, Symbol};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::{self, Read};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use tree_sitter::{Language, PropertySheet};
 
 const PACKAGE_JSON_PATH: &'static str = "package.json";
+const TREE_SITTER_JSON_PATH: &'static str = "tree-sitter.json";
 const PARSER_C_PATH: &'static str = "src/parser.c";
 const SCANNER_C_PATH: &'static str = "src/scanner.c";
 const SCANNER_CC_PATH: &'static str = "src/scanner.cc";
+const SCANNER_DIR_PATH: &'static str = "src/scanner";
 const DEFINITIONS_JSON_PATH: &'static str = "src/definitions.json";
+const EMPTY_PROPERTY_SHEET_JSON: &'static str = r#"{
+  "states": [{"transitions": [], "property_set_id": 0, "default_next_state_id": 0}],
+  "property_sets": [{}]
+}"#;
+
+const HASH_EXTENSION: &'static str = "hash";
+const PROPERTY_SHEET_CACHE_EXTENSION: &'static str = "definitions.json";
+const MAX_SHEBANG_LENGTH: u64 = 256;
+const COMPILE_THREAD_COUNT: usize = 4;
+
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[cfg(unix)]
 const DYLIB_EXTENSION: &'static str = "so";
@@ -21,11 +40,24 @@ const DYLIB_EXTENSION: &'static str = "dll";
 
 pub struct LanguageRegistry {
     parser_src_paths: Vec<PathBuf>,
+    grammar_paths: Vec<PathBuf>,
     parser_lib_path: PathBuf,
-    language_names_by_extension: HashMap<String, (String, PathBuf)>,
+    file_types: FileTypes,
+    language_paths_by_name: HashMap<String, PathBuf>,
     loaded_languages: HashMap<String, (Library, Language, Arc<PropertySheet>)>,
 }
 
+// The tables that determine which language a file is written in. Crawlers keep
+// their own copy of these, so that they don't need to lock the registry to
+// look up the language of every file.
+#[derive(Clone, Default)]
+pub struct FileTypes {
+    language_names_by_extension: HashMap<String, (String, PathBuf)>,
+    language_names_by_filename: HashMap<String, String>,
+    language_names_by_interpreter: HashMap<String, String>,
+    language_globs: Vec<(String, GlobMatcher, String)>,
+}
+
 unsafe impl Send for LanguageRegistry {}
 unsafe impl Sync for LanguageRegistry {}
 
@@ -34,119 +66,830 @@ impl LanguageRegistry {
         LanguageRegistry {
             parser_lib_path,
             parser_src_paths,
-            language_names_by_extension: HashMap::new(),
+            grammar_paths: Vec::new(),
+            file_types: FileTypes::default(),
+            language_paths_by_name: HashMap::new(),
             loaded_languages: HashMap::new(),
         }
     }
 
+    pub fn add_grammar_path(&mut self, language_path: PathBuf) {
+        self.grammar_paths.push(language_path);
+    }
+
+    // Globs take precedence over file extensions. When several globs match
+    // a path, the longest pattern is treated as the most specific one.
+    pub fn add_language_glob(&mut self, pattern: &str, name: &str) -> Result<(), globset::Error> {
+        let matcher = Glob::new(pattern)?.compile_matcher();
+        let globs = &mut self.file_types.language_globs;
+        globs.push((pattern.to_owned(), matcher, name.to_owned()));
+        globs.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Ok(())
+    }
+
+    // When several grammars declare the same file type, the one that is
+    // registered last takes precedence. Grammars are registered in the order
+    // of the parser directories, followed by the individual grammar paths.
     pub fn load_parsers(&mut self) -> io::Result<()> {
+        let mut language_paths = Vec::new();
         for parser_container_dir in self.parser_src_paths.iter() {
-            for entry in fs::read_dir(parser_container_dir)? {
+            let entries = match fs::read_dir(parser_container_dir) {
+                Ok(entries) => entries,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
                 let entry = entry?;
                 if let Some(parser_dir_name) = entry.file_name().to_str() {
                     if parser_dir_name.starts_with("tree-sitter-") {
-                        let name = parser_dir_name.split_at("tree-sitter-".len()).1;
-                        let language_path = entry.path();
-                        match file_extensions_for_language_path(&language_path) {
-                            Ok(None) => {},
-                            Ok(Some(extensions)) => {
-                                for extension in extensions {
-                                    self.language_names_by_extension.insert(
-                                        extension.to_owned(),
-                                        (name.to_owned(), entry.path())
-                                    );
-                                }
-                            },
-                            Err(e) => {
-                                eprintln!("{}: {}", parser_dir_name, e);
-                            }
-                        }
+                        language_paths.push(entry.path());
                     }
                 }
             }
         }
+        language_paths.extend(self.grammar_paths.iter().cloned());
+        for language_path in language_paths {
+            self.register_language_path(&language_path);
+        }
         Ok(())
     }
 
-    pub fn language_for_file_extension(&mut self, extension: &str) -> io::Result<Option<(Language, Arc<PropertySheet>)>> {
-        if let Some((name, path)) = self.language_names_by_extension.get(extension) {
-            if let Some((_, language, sheet)) = self.loaded_languages.get(name) {
-                return Ok(Some((*language, sheet.clone())));
+    pub fn load_grammar(&mut self, language_path: &Path) -> io::Result<String> {
+        let name = self.register_language_path(language_path).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid grammar directory name")
+        })?;
+        self.load_language_at_path(&name, language_path)?;
+        Ok(name)
+    }
+
+    fn register_language_path(&mut self, language_path: &Path) -> Option<String> {
+        let parser_dir_name = language_path.file_name()?.to_str()?;
+        let name = if parser_dir_name.starts_with("tree-sitter-") {
+            parser_dir_name.split_at("tree-sitter-".len()).1
+        } else {
+            parser_dir_name
+        };
+        self.language_paths_by_name.insert(name.to_owned(), language_path.to_owned());
+        match tree_sitter_json_for_language_path(language_path) {
+            Ok(None) => {},
+            Ok(Some(tree_sitter_json)) => {
+                for extension in file_extensions(tree_sitter_json.file_types.unwrap_or_default()) {
+                    self.file_types.language_names_by_extension.insert(
+                        extension,
+                        (name.to_owned(), language_path.to_owned())
+                    );
+                }
+                for file_name in tree_sitter_json.file_names.unwrap_or_default() {
+                    self.file_types.language_names_by_filename.insert(file_name, name.to_owned());
+                }
+                for interpreter in tree_sitter_json.interpreters.unwrap_or_default() {
+                    let names_by_interpreter = &mut self.file_types.language_names_by_interpreter;
+                    names_by_interpreter.insert(interpreter, name.to_owned());
+                }
+            },
+            Err(e) => {
+                eprintln!("{}: {}", parser_dir_name, e);
             }
-            self.load_language_at_path(&name.clone(), &path.clone())
+        }
+        Some(name.to_owned())
+    }
+
+    pub fn has_language_name(&self, name: &str) -> bool {
+        self.language_paths_by_name.contains_key(name)
+    }
+
+    pub fn language_name_for_path(&self, path: &Path) -> Option<&str> {
+        self.file_types.language_name_for_path(path)
+    }
+
+    pub fn file_types(&self) -> FileTypes {
+        self.file_types.clone()
+    }
+
+    pub fn language_path(&self, name: &str) -> Option<&Path> {
+        self.language_paths_by_name.get(name).map(|path| path.as_path())
+    }
+
+    pub fn language_for_name(
+        &mut self,
+        name: &str,
+    ) -> io::Result<Option<(Language, Arc<PropertySheet>)>> {
+        if let Some((_, language, sheet)) = self.loaded_languages.get(name) {
+            return Ok(Some((*language, sheet.clone())));
+        }
+        if let Some(path) = self.language_paths_by_name.get(name).cloned() {
+            self.load_language_at_path(name, &path)
         } else {
             Ok(None)
         }
     }
 
+    // Compile every registered language whose library is missing or out of
+    // date, several at a time. Otherwise, languages are compiled one at a
+    // time when they are first loaded.
+    //
+    // A grammar that fails to compile is reported and unregistered, so that
+    // its files are skipped and the other languages can still be indexed.
+    // This only fails if none of the registered languages are left.
+    pub fn compile_languages(&mut self) -> io::Result<()> {
+        let mut jobs = Vec::new();
+        let mut failures = Vec::new();
+        for (name, language_path) in self.language_paths_by_name.iter() {
+            if self.loaded_languages.contains_key(name) {
+                continue;
+            }
+            let library_path = self.library_path(name);
+            match needs_compile(language_path, &library_path) {
+                Ok(Some(source_hash)) => {
+                    jobs.push((name.clone(), language_path.clone(), library_path, source_hash))
+                }
+                Ok(None) => {}
+                Err(e) => failures.push((name.clone(), e)),
+            }
+        }
+
+        let jobs = Arc::new(Mutex::new(jobs));
+        let threads = (0..COMPILE_THREAD_COUNT)
+            .map(|_| {
+                let jobs = jobs.clone();
+                thread::spawn(move || {
+                    let mut failures = Vec::new();
+                    loop {
+                        let job = jobs.lock().unwrap().pop();
+                        match job {
+                            Some((name, language_path, library_path, source_hash)) => {
+                                let result = compile_language(
+                                    &name,
+                                    &language_path,
+                                    &library_path,
+                                    source_hash,
+                                );
+                                if let Err(e) = result {
+                                    failures.push((name, e));
+                                }
+                            }
+                            None => return failures,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            failures.extend(thread.join().unwrap());
+        }
+
+        let language_count = self.language_paths_by_name.len();
+        for (name, error) in failures.iter() {
+            eprintln!("Skipping the {} grammar. {}", name, error);
+            self.language_paths_by_name.remove(name);
+        }
+        if language_count > 0 && self.language_paths_by_name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "None of the grammars could be compiled",
+            ));
+        }
+        Ok(())
+    }
+
+    fn library_path(&self, name: &str) -> PathBuf {
+        let mut library_path = self.parser_lib_path.join(name);
+        library_path.set_extension(DYLIB_EXTENSION);
+        library_path
+    }
+
     fn load_language_at_path(
         &mut self,
         name: &str,
         language_path: &Path,
     ) -> io::Result<Option<(Language, Arc<PropertySheet>)>> {
-        let parser_c_path = language_path.join(PARSER_C_PATH);
-        let mut library_path = self.parser_lib_path.join(name);
-        library_path.set_extension(DYLIB_EXTENSION);
-
-        if !library_path.exists() || was_modified_more_recently(&parser_c_path, &library_path)? {
-            let compiler_name = std::env::var("CXX").unwrap_or("c++".to_owned());
-            let mut command = Command::new(compiler_name);
-            command
-                .arg("-shared")
-                .arg("-fPIC")
-                .arg("-I")
-                .arg(language_path.join("src"))
-                .arg("-o")
-                .arg(&library_path)
-                .arg("-xc")
-                .arg(parser_c_path);
-            let scanner_c_path = language_path.join(SCANNER_C_PATH);
-            let scanner_cc_path = language_path.join(SCANNER_CC_PATH);
-            if scanner_c_path.exists() {
-                command.arg("-xc").arg(scanner_c_path);
-            } else if scanner_cc_path.exists() {
-                command.arg("-xc++").arg(scanner_cc_path);
-            }
-            command.output()?;
+        let library_path = self.library_path(name);
+        if let Some(source_hash) = needs_compile(language_path, &library_path)? {
+            compile_language(name, language_path, &library_path, source_hash)?;
         }
 
-        let library = Library::new(library_path)?;
+        // A library that is missing some of its scanner's sources can fail
+        // to load because of an undefined symbol, or lack the language function.
+        let library = Library::new(&library_path).map_err(|e| {
+            incomplete_library_error(language_path, &library_path, e)
+        })?;
         let language_fn_name = "tree_sitter_".to_owned() + name;
         let language = unsafe {
-            let language_fn: Symbol<unsafe extern "C" fn() -> Language> =
-                library.get(language_fn_name.as_bytes())?;
+            let language_fn: Symbol<unsafe extern "C" fn() -> Language> = library
+                .get(language_fn_name.as_bytes())
+                .map_err(|e| incomplete_library_error(language_path, &library_path, e))?;
             language_fn()
         };
 
-        let mut property_sheet_string = String::new();
-        let mut property_sheet_file = File::open(language_path.join(DEFINITIONS_JSON_PATH))?;
-        property_sheet_file.read_to_string(&mut property_sheet_string)?;
+        // Grammars without a property sheet can still be parsed, but none of
+        // their nodes have any tagging properties.
+        let property_sheet_string = match property_sheet_json(language_path, &library_path)? {
+            Some(property_sheet_string) => property_sheet_string,
+            None => {
+                eprintln!("{} has no {}, so it won't be tagged", name, DEFINITIONS_JSON_PATH);
+                EMPTY_PROPERTY_SHEET_JSON.to_owned()
+            }
+        };
         let property_sheet = Arc::new(PropertySheet::new(language, &property_sheet_string)?);
         self.loaded_languages.insert(name.to_string(), (library, language, property_sheet.clone()));
         Ok(Some((language, property_sheet)))
     }
 }
 
-fn file_extensions_for_language_path(path: &Path) -> io::Result<Option<Vec<String>>> {
-    #[derive(Deserialize)]
-    struct TreeSitterJSON {
-        #[serde(rename = "file-types")]
-        file_types: Option<Vec<String>>
+impl FileTypes {
+    pub fn language_name_for_path(&self, path: &Path) -> Option<&str> {
+        for (pattern, matcher, name) in self.language_globs.iter() {
+            let is_match = if pattern.contains('/') {
+                matcher.is_match(path)
+            } else {
+                path.file_name().map_or(false, |file_name| matcher.is_match(file_name))
+            };
+            if is_match {
+                return Some(name.as_str());
+            }
+        }
+
+        // Some files, like `Makefile`, are recognized by their whole name.
+        let file_name = path.file_name().and_then(|file_name| file_name.to_str());
+        if let Some(name) = file_name.and_then(|n| self.language_names_by_filename.get(n)) {
+            return Some(name.as_str());
+        }
+
+        match path.extension() {
+            Some(extension) => extension
+                .to_str()
+                .and_then(|extension| {
+                    self.language_names_by_extension.get(&extension.to_lowercase())
+                })
+                .map(|(name, _)| name.as_str()),
+
+            // Scripts without an extension are recognized by the interpreter
+            // named in their shebang line.
+            None => {
+                let interpreter = shebang_interpreter(path)?;
+                self.language_names_by_interpreter
+                    .get(&interpreter)
+                    .or_else(|| {
+                        let unversioned = interpreter.trim_end_matches(|c: char| {
+                            c.is_ascii_digit() || c == '.'
+                        });
+                        self.language_names_by_interpreter.get(unversioned)
+                    })
+                    .map(|name| name.as_str())
+            }
+        }
     }
+}
+
+pub fn is_grammar_path(path: &Path) -> bool {
+    (path.join(PACKAGE_JSON_PATH).is_file() || path.join(TREE_SITTER_JSON_PATH).is_file())
+        && path.join(PARSER_C_PATH).is_file()
+}
 
+#[derive(Deserialize)]
+struct TreeSitterJSON {
+    #[serde(rename = "file-types")]
+    file_types: Option<Vec<String>>,
+    #[serde(rename = "file-names")]
+    file_names: Option<Vec<String>>,
+    #[serde(rename = "scanner-sources")]
+    scanner_sources: Option<Vec<PathBuf>>,
+    #[serde(rename = "include-dirs")]
+    include_dirs: Option<Vec<PathBuf>>,
+    interpreters: Option<Vec<String>>,
+}
+
+// Newer grammars describe themselves in a `tree-sitter.json` file, which
+// takes precedence over the `tree-sitter` section of their `package.json`.
+fn tree_sitter_json_for_language_path(path: &Path) -> io::Result<Option<TreeSitterJSON>> {
     #[derive(Deserialize)]
     struct PackageJSON {
         #[serde(rename = "tree-sitter")]
         tree_sitter: Option<TreeSitterJSON>
     }
 
+    #[derive(Deserialize)]
+    struct TreeSitterConfigJSON {
+        #[serde(default)]
+        grammars: Vec<TreeSitterJSON>,
+    }
+
+    match fs::read_to_string(path.join(TREE_SITTER_JSON_PATH)) {
+        Ok(contents) => {
+            let config: TreeSitterConfigJSON = serde_json::from_str(&contents)?;
+            return Ok(config.grammars.into_iter().next());
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
     let mut package_json_contents = String::new();
     let mut package_json_file = File::open(path.join(PACKAGE_JSON_PATH))?;
     package_json_file.read_to_string(&mut package_json_contents)?;
     let package_json: PackageJSON = serde_json::from_str(&package_json_contents)?;
-    Ok(package_json.tree_sitter.and_then(|t| t.file_types))
+    Ok(package_json.tree_sitter)
+}
+
+// Grammars list their file types in several forms, like `js`, `.js` or
+// `*.js`. These are all stored as bare, lowercase extensions, so that they can
+// be matched against the extension of a path.
+fn file_extensions(file_types: Vec<String>) -> Vec<String> {
+    file_types
+        .iter()
+        .map(|file_type| file_type.trim_start_matches('*').trim_start_matches('.'))
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| extension.to_lowercase())
+        .collect()
+}
+
+// Returns the name of the program in a file's `#!` line, looking past `env`,
+// as in `#!/usr/bin/env python3`. The line may follow a byte order mark and
+// end with `\r\n`.
+fn shebang_interpreter(path: &Path) -> Option<String> {
+    let mut first_line = String::new();
+    BufReader::new(File::open(path).ok()?.take(MAX_SHEBANG_LENGTH))
+        .read_line(&mut first_line)
+        .ok()?;
+    let first_line = first_line.trim_start_matches('\u{feff}');
+    if !first_line.starts_with("#!") {
+        return None;
+    }
+    let mut words = first_line[2..].split_whitespace();
+    let mut program = Path::new(words.next()?).file_name()?.to_str()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    Some(program.to_owned())
+}
+
+// Grammars can list the source files of their external scanners, relative to
+// the grammar's directory, in the `scanner-sources` field of the `tree-sitter`
+// section of their `package.json`. Otherwise, a single `scanner.c` or
+// `scanner.cc` file is used if it exists, or else all of the C and C++ files
+// in a `src/scanner` directory.
+fn scanner_paths(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let tree_sitter_json = optional_tree_sitter_json_for_language_path(path)?;
+    if let Some(scanner_sources) = tree_sitter_json.and_then(|t| t.scanner_sources) {
+        return Ok(scanner_sources.iter().map(|source| path.join(source)).collect());
+    }
+
+    let scanner_c_path = path.join(SCANNER_C_PATH);
+    let scanner_cc_path = path.join(SCANNER_CC_PATH);
+    let scanner_dir_path = path.join(SCANNER_DIR_PATH);
+    if scanner_c_path.exists() {
+        Ok(vec![scanner_c_path])
+    } else if scanner_cc_path.exists() {
+        Ok(vec![scanner_cc_path])
+    } else if scanner_dir_path.is_dir() {
+        let mut scanner_paths = Vec::new();
+        for entry in fs::read_dir(scanner_dir_path)? {
+            let entry_path = entry?.path();
+            let is_c = entry_path.extension().map_or(false, |extension| extension == "c");
+            if is_c || is_cpp_path(&entry_path) {
+                scanner_paths.push(entry_path);
+            }
+        }
+        scanner_paths.sort();
+        Ok(scanner_paths)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+// Besides their `src` directory, grammars can list other directories to
+// search for headers in the `include-dirs` field.
+fn include_paths(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut include_paths = vec![path.join("src")];
+    if let Some(tree_sitter_json) = optional_tree_sitter_json_for_language_path(path)? {
+        let include_dirs = tree_sitter_json.include_dirs.unwrap_or_default();
+        include_paths.extend(include_dirs.iter().map(|dir| path.join(dir)));
+    }
+    Ok(include_paths)
+}
+
+fn optional_tree_sitter_json_for_language_path(path: &Path) -> io::Result<Option<TreeSitterJSON>> {
+    match tree_sitter_json_for_language_path(path) {
+        Ok(tree_sitter_json) => Ok(tree_sitter_json),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// A library is reused if it is newer than all of the grammar's sources. If
+// not, it is still reused if the sources' contents haven't changed since it
+// was compiled, as when a `git checkout` has updated their timestamps. Returns
+// the hash of the sources if the library needs to be compiled. The hash isn't
+// guaranteed to be stable across Rust versions, which at worst causes an extra
+// compilation.
+fn needs_compile(language_path: &Path, library_path: &Path) -> io::Result<Option<u64>> {
+    let source_paths = source_paths(language_path)?;
+    if library_path.exists() {
+        let mut is_newer = true;
+        for source_path in source_paths.iter() {
+            if was_modified_more_recently(source_path, library_path)? {
+                is_newer = false;
+            }
+        }
+        if is_newer {
+            return Ok(None);
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    for source_path in source_paths.iter() {
+        fs::read(source_path)?.hash(&mut hasher);
+    }
+    let source_hash = hasher.finish();
+    if library_path.exists() {
+        let stored_hash = fs::read_to_string(library_path.with_extension(HASH_EXTENSION)).ok();
+        if stored_hash == Some(source_hash.to_string()) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(source_hash))
+}
+
+fn compile_language(
+    name: &str,
+    language_path: &Path,
+    library_path: &Path,
+    source_hash: u64,
+) -> io::Result<()> {
+    if let Some(parser_lib_path) = library_path.parent() {
+        fs::create_dir_all(parser_lib_path)?;
+    }
+    let default_compiler_name = if cfg!(windows) { "cl" } else { "c++" };
+    let compiler_name = std::env::var("CXX").unwrap_or(default_compiler_name.to_owned());
+    let mut command = Command::new(&compiler_name);
+    if is_msvc(&compiler_name) {
+        // MSVC writes its intermediate files to the working directory.
+        if let Some(parser_lib_path) = library_path.parent() {
+            command.current_dir(parser_lib_path);
+        }
+        let mut output_arg = OsString::from("/Fe");
+        output_arg.push(library_path);
+        command.arg("/nologo").arg("/LD").arg(output_arg);
+        for include_path in include_paths(language_path)? {
+            command.arg("/I").arg(include_path);
+        }
+        command.arg("/Tc").arg(language_path.join(PARSER_C_PATH));
+        for scanner_path in scanner_paths(language_path)? {
+            command.arg(if is_cpp_path(&scanner_path) { "/Tp" } else { "/Tc" }).arg(scanner_path);
+        }
+        command.arg("/link").arg(format!("/EXPORT:tree_sitter_{}", name));
+    } else {
+        command.arg("-shared").arg("-fPIC");
+        for include_path in include_paths(language_path)? {
+            command.arg("-I").arg(include_path);
+        }
+        command
+            .arg("-o")
+            .arg(library_path)
+            .arg("-xc")
+            .arg(language_path.join(PARSER_C_PATH));
+        for scanner_path in scanner_paths(language_path)? {
+            command.arg(if is_cpp_path(&scanner_path) { "-xc++" } else { "-xc" }).arg(scanner_path);
+        }
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Failed to compile {}:\n{}",
+                language_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ),
+        ));
+    }
+    fs::write(library_path.with_extension(HASH_EXTENSION), source_hash.to_string())
+}
+
+// A `PropertySheet` can only be built from JSON text, and generated property
+// sheets are pretty-printed and can be several megabytes. So a compact copy
+// is cached next to the compiled library, along with the hash of the
+// original, and used until the grammar's `definitions.json` changes.
+fn property_sheet_json(language_path: &Path, library_path: &Path) -> io::Result<Option<String>> {
+    let contents = match fs::read_to_string(language_path.join(DEFINITIONS_JSON_PATH)) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    let hash = hasher.finish().to_string();
+
+    let cache_path = library_path.with_extension(PROPERTY_SHEET_CACHE_EXTENSION);
+    if let Ok(cached) = fs::read_to_string(&cache_path) {
+        if let Some(newline_index) = cached.find('\n') {
+            if cached[..newline_index] == hash {
+                return Ok(Some(cached[newline_index + 1..].to_owned()));
+            }
+        }
+    }
+
+    // The cache is written to a temporary file and then moved into place, so
+    // that another registry loading the same grammar never reads part of it.
+    let json = serde_json::from_str::<serde_json::Value>(&contents)?.to_string();
+    let temp_path = cache_path.with_extension(format!(
+        "{}.{}-{}.tmp",
+        PROPERTY_SHEET_CACHE_EXTENSION,
+        std::process::id(),
+        TEMP_FILE_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::write(&temp_path, format!("{}\n{}", hash, json))?;
+    fs::rename(&temp_path, &cache_path)?;
+    Ok(Some(json))
+}
+
+fn incomplete_library_error(
+    language_path: &Path,
+    library_path: &Path,
+    error: io::Error,
+) -> io::Error {
+    io::Error::new(
+        error.kind(),
+        format!(
+            "Failed to load {}: {}. If the grammar's external scanner has sources other than \
+             src/scanner.c or src/scanner.cc, list them in the scanner-sources field of {}",
+            library_path.display(),
+            error,
+            language_path.join(PACKAGE_JSON_PATH).display()
+        ),
+    )
+}
+
+fn is_msvc(compiler_name: &str) -> bool {
+    let stem = Path::new(compiler_name).file_stem().and_then(|stem| stem.to_str());
+    stem.map_or(false, |stem| {
+        stem.eq_ignore_ascii_case("cl") || stem.eq_ignore_ascii_case("clang-cl")
+    })
+}
+
+fn is_cpp_path(path: &Path) -> bool {
+    path.extension().map_or(false, |extension| {
+        extension == "cc" || extension == "cpp" || extension == "cxx"
+    })
+}
+
+fn source_paths(language_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut source_paths = vec![language_path.join(PARSER_C_PATH)];
+    source_paths.extend(scanner_paths(language_path)?);
+    Ok(source_paths)
 }
 
 fn was_modified_more_recently(a: &Path, b: &Path) -> io::Result<bool> {
     Ok(fs::metadata(a)?.modified()? > fs::metadata(b)?.modified()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    #[test]
+    fn test_language_name_for_path_with_overlapping_globs() {
+        let dir = TempDir::new();
+        let parsers_path = dir.path().join("parsers");
+        write_grammar(&parsers_path, "javascript", &["js"]);
+        write_grammar(&parsers_path, "typescript", &["ts"]);
+        let mut registry = LanguageRegistry::new(dir.path().join("compiled"), vec![parsers_path]);
+        registry.load_parsers().unwrap();
+        registry.add_language_glob("*.d.ts", "typescript-declarations").unwrap();
+        registry.add_language_glob("*.config.js", "config").unwrap();
+        registry.add_language_glob("webpack.config.js", "webpack").unwrap();
+        registry.add_language_glob("**/generated/*.js", "generated").unwrap();
+
+        // The longest matching glob wins, and extensions are only used when
+        // no glob matches.
+        let file_types = registry.file_types();
+        for (path, language_name) in &[
+            ("/project/index.js", "javascript"),
+            ("/project/index.ts", "typescript"),
+            ("/project/index.d.ts", "typescript-declarations"),
+            ("/project/babel.config.js", "config"),
+            ("/project/webpack.config.js", "webpack"),
+            ("/project/generated/index.js", "generated"),
+            ("/project/generated/webpack.config.js", "generated"),
+        ] {
+            assert_eq!(
+                file_types.language_name_for_path(Path::new(path)),
+                Some(*language_name),
+                "wrong language for {}",
+                path
+            );
+        }
+        assert_eq!(file_types.language_name_for_path(Path::new("/project/index.rb")), None);
+    }
+
+    #[test]
+    fn test_file_types_are_normalized_to_lowercase_extensions() {
+        let dir = TempDir::new();
+        let parsers_path = dir.path().join("parsers");
+        write_grammar(&parsers_path, "javascript", &["js", ".MJS", "*.jsx", "*"]);
+        let mut registry = LanguageRegistry::new(dir.path().join("compiled"), vec![parsers_path]);
+        registry.load_parsers().unwrap();
+
+        let file_types = registry.file_types();
+        for path in &["/a/index.js", "/a/index.mjs", "/a/index.JSX", "/a/INDEX.Js"] {
+            assert_eq!(
+                file_types.language_name_for_path(Path::new(path)),
+                Some("javascript"),
+                "wrong language for {}",
+                path
+            );
+        }
+        assert_eq!(file_types.language_name_for_path(Path::new("/a/index.ts")), None);
+    }
+
+    #[test]
+    fn test_tree_sitter_json_takes_precedence_over_package_json() {
+        let dir = TempDir::new();
+        let parsers_path = dir.path().join("parsers");
+        write_grammar(&parsers_path, "javascript", &["js"]);
+        let tree_sitter_json = serde_json::json!({
+            "grammars": [{ "name": "javascript", "file-types": ["mjs"] }]
+        });
+        let grammar_path = parsers_path.join("tree-sitter-javascript");
+        fs::write(grammar_path.join(TREE_SITTER_JSON_PATH), tree_sitter_json.to_string()).unwrap();
+
+        let ruby_path = parsers_path.join("tree-sitter-ruby");
+        fs::create_dir_all(ruby_path.join("src")).unwrap();
+        let tree_sitter_json = serde_json::json!({
+            "grammars": [{ "name": "ruby", "file-types": ["rb"] }]
+        });
+        fs::write(ruby_path.join(TREE_SITTER_JSON_PATH), tree_sitter_json.to_string()).unwrap();
+        assert!(!is_grammar_path(&ruby_path));
+        fs::write(ruby_path.join(PARSER_C_PATH), "").unwrap();
+        assert!(is_grammar_path(&ruby_path));
+
+        let mut registry = LanguageRegistry::new(dir.path().join("compiled"), vec![parsers_path]);
+        registry.load_parsers().unwrap();
+        let file_types = registry.file_types();
+        assert_eq!(file_types.language_name_for_path(Path::new("/a/b.mjs")), Some("javascript"));
+        assert_eq!(file_types.language_name_for_path(Path::new("/a/b.js")), None);
+        assert_eq!(file_types.language_name_for_path(Path::new("/a/b.rb")), Some("ruby"));
+    }
+
+    #[test]
+    fn test_shebang_interpreter_with_byte_order_mark_and_crlf() {
+        let dir = TempDir::new();
+        let path = dir.path().join("script");
+        fs::write(&path, "\u{feff}#!/usr/bin/env ruby\r\nputs 1\r\n").unwrap();
+        assert_eq!(shebang_interpreter(&path), Some("ruby".to_owned()));
+        fs::write(&path, "#!/bin/bash\r\necho 1\r\n").unwrap();
+        assert_eq!(shebang_interpreter(&path), Some("bash".to_owned()));
+    }
+
+    #[test]
+    fn test_language_name_for_path_with_shebang() {
+        let dir = TempDir::new();
+        let parsers_path = dir.path().join("parsers");
+        for (name, interpreter) in &[("ruby", "ruby"), ("python", "python")] {
+            let grammar_path = parsers_path.join(format!("tree-sitter-{}", name));
+            fs::create_dir_all(&grammar_path).unwrap();
+            let tree_sitter_json = serde_json::json!({
+                "grammars": [{ "name": name, "file-types": [], "interpreters": [interpreter] }]
+            });
+            fs::write(grammar_path.join(TREE_SITTER_JSON_PATH), tree_sitter_json.to_string())
+                .unwrap();
+        }
+        let mut registry = LanguageRegistry::new(dir.path().join("compiled"), vec![parsers_path]);
+        registry.load_parsers().unwrap();
+
+        let file_types = registry.file_types();
+        for (shebang, language_name) in &[
+            ("#!/usr/bin/env ruby", Some("ruby")),
+            ("#!/usr/bin/ruby -w", Some("ruby")),
+            ("#!/usr/bin/env python3", Some("python")),
+            ("#!/usr/local/bin/python3.11", Some("python")),
+            ("#!/usr/bin/env -S ruby --disable-gems", Some("ruby")),
+            ("#!/usr/bin/env RUBYOPT=-w ruby", Some("ruby")),
+            ("#!/bin/sh", None),
+            ("# not a shebang", None),
+        ] {
+            let path = dir.write("bin/setup", &format!("{}\nputs 1\n", shebang));
+            assert_eq!(
+                file_types.language_name_for_path(&path),
+                *language_name,
+                "wrong language for {}",
+                shebang
+            );
+        }
+
+        // Shebangs are only read when a file has no extension.
+        let path = dir.write("bin/setup.txt", "#!/usr/bin/env ruby\n");
+        assert_eq!(file_types.language_name_for_path(&path), None);
+    }
+
+    #[test]
+    fn test_scanner_paths() {
+        let dir = TempDir::new();
+        let parsers_path = dir.path().join("parsers");
+        write_grammar(&parsers_path, "a", &["a"]);
+        let grammar_path = parsers_path.join("tree-sitter-a");
+        assert!(scanner_paths(&grammar_path).unwrap().is_empty());
+
+        fs::create_dir_all(grammar_path.join(SCANNER_DIR_PATH)).unwrap();
+        for name in &["tokens.cc", "scanner.c", "README.md"] {
+            fs::write(grammar_path.join(SCANNER_DIR_PATH).join(name), "").unwrap();
+        }
+        assert_eq!(
+            scanner_paths(&grammar_path).unwrap(),
+            vec![
+                grammar_path.join("src/scanner/scanner.c"),
+                grammar_path.join("src/scanner/tokens.cc"),
+            ]
+        );
+
+        fs::write(grammar_path.join(SCANNER_CC_PATH), "").unwrap();
+        assert_eq!(scanner_paths(&grammar_path).unwrap(), vec![grammar_path.join(SCANNER_CC_PATH)]);
+        fs::write(grammar_path.join(SCANNER_C_PATH), "").unwrap();
+        assert_eq!(scanner_paths(&grammar_path).unwrap(), vec![grammar_path.join(SCANNER_C_PATH)]);
+
+        // Sources listed in the package.json take precedence.
+        let package_json = serde_json::json!({
+            "tree-sitter": { "scanner-sources": ["src/a.c", "src/b.cc"] }
+        });
+        fs::write(grammar_path.join(PACKAGE_JSON_PATH), package_json.to_string()).unwrap();
+        assert_eq!(
+            scanner_paths(&grammar_path).unwrap(),
+            vec![grammar_path.join("src/a.c"), grammar_path.join("src/b.cc")]
+        );
+    }
+
+    #[test]
+    fn test_compile_languages_skips_grammars_that_fail_to_compile() {
+        let dir = TempDir::new();
+        let parsers_path = dir.path().join("parsers");
+        write_grammar(&parsers_path, "broken", &["broken"]);
+        let broken_parser_path = parsers_path.join("tree-sitter-broken").join(PARSER_C_PATH);
+        fs::create_dir_all(broken_parser_path.parent().unwrap()).unwrap();
+        fs::write(&broken_parser_path, "this is not C\n").unwrap();
+
+        let mut registry =
+            LanguageRegistry::new(dir.path().join("compiled"), vec![parsers_path.clone()]);
+        registry.load_parsers().unwrap();
+        assert!(registry.compile_languages().is_err());
+
+        write_grammar(&parsers_path, "good", &["good"]);
+        let good_parser_path = parsers_path.join("tree-sitter-good").join(PARSER_C_PATH);
+        fs::create_dir_all(good_parser_path.parent().unwrap()).unwrap();
+        fs::write(&good_parser_path, "void *tree_sitter_good(void) { return 0; }\n").unwrap();
+
+        let mut registry = LanguageRegistry::new(dir.path().join("compiled"), vec![parsers_path]);
+        registry.load_parsers().unwrap();
+        registry.compile_languages().unwrap();
+        assert!(registry.has_language_name("good"));
+        assert!(!registry.has_language_name("broken"));
+        assert!(registry.library_path("good").exists());
+    }
+
+    #[test]
+    fn test_grammars_without_definitions_json() {
+        let dir = TempDir::new();
+        let mut registry = test_util::javascript_registry(&dir, None);
+        let grammar_path = registry.language_path("javascript").unwrap().to_owned();
+        assert!(!grammar_path.join(DEFINITIONS_JSON_PATH).exists());
+
+        // The grammar is loaded with an empty property sheet, which this
+        // version of tree-sitter must accept.
+        let (language, _) = registry.language_for_name("javascript").unwrap().unwrap();
+        PropertySheet::new(language, EMPTY_PROPERTY_SHEET_JSON).unwrap();
+    }
+
+    #[test]
+    fn test_property_sheet_json_is_cached() {
+        let dir = TempDir::new();
+        let language_path = dir.path().join("tree-sitter-javascript");
+        let library_path = dir.path().join("compiled/javascript.so");
+        assert_eq!(property_sheet_json(&language_path, &library_path).unwrap(), None);
+
+        let load = || {
+            let json = property_sheet_json(&language_path, &library_path).unwrap().unwrap();
+            assert!(!json.contains('\n'));
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()
+        };
+        fs::create_dir_all(library_path.parent().unwrap()).unwrap();
+        let definitions_path = language_path.join(DEFINITIONS_JSON_PATH);
+        fs::create_dir_all(definitions_path.parent().unwrap()).unwrap();
+        fs::write(&definitions_path, "{\n  \"states\": [],\n  \"property_sets\": []\n}\n").unwrap();
+        assert_eq!(load(), serde_json::json!({ "states": [], "property_sets": [] }));
+
+        // The cached copy is used while the original is unchanged.
+        let cache_path = library_path.with_extension(PROPERTY_SHEET_CACHE_EXTENSION);
+        let cached = fs::read_to_string(&cache_path).unwrap();
+        fs::write(&cache_path, cached.replace("[]", "[{}]")).unwrap();
+        assert_eq!(load(), serde_json::json!({ "states": [{}], "property_sets": [{}] }));
+
+        fs::write(&definitions_path, r#"{"states": [], "property_sets": [{}]}"#).unwrap();
+        assert_eq!(load(), serde_json::json!({ "states": [], "property_sets": [{}] }));
+    }
+
+    fn write_grammar(parsers_path: &Path, name: &str, file_types: &[&str]) {
+        let grammar_path = parsers_path.join(format!("tree-sitter-{}", name));
+        fs::create_dir_all(&grammar_path).unwrap();
+        let package_json = serde_json::json!({ "tree-sitter": { "file-types": file_types } });
+        fs::write(grammar_path.join(PACKAGE_JSON_PATH), package_json.to_string()).unwrap();
+    }
+}
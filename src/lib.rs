@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate serde_derive;
+
+pub mod config;
+pub mod crawler;
+pub mod ctags;
+pub mod language_registry;
+pub mod project;
+pub mod store;
+
+#[cfg(test)]
+mod test_util;
+
+pub use crate::config::Config;
+pub use crate::crawler::{CrawlOptions, DirCrawler, Error, FileProfile, Result};
+pub use crate::language_registry::LanguageRegistry;
+pub use crate::project::{DefinitionSearch, Project, ProjectOptions};
+pub use crate::store::{Definition, IndexStats, LanguageStats, Location, Store};
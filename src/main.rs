@@ -1,93 +1,1614 @@
-#[macro_use]
-extern crate serde_derive;
+mod server;
+mod watch;
 
-mod crawler;
-mod language_registry;
-mod store;
-
-use std::io;
-use std::path::PathBuf;
-use clap::{App, Arg, SubCommand};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use regex::RegexSet;
 use tree_sitter::Point;
+use tree_tags::{config, crawler, ctags, language_registry, project, store};
+
+const DEFAULT_MAX_NODE_COUNT: usize = 1_000_000;
+const DEFAULT_MAX_FILE_SIZE: u64 = 2 * 1024 * 1024;
+const UTF8_BYTE_ORDER_MARK: &'static [u8] = b"\xef\xbb\xbf";
 
 fn main() -> crawler::Result<()> {
     let matches = App::new("Tree-tags")
         .version("0.1")
         .author("Max Brunsfeld <maxbrunsfeld@gmail.com>")
         .about("Indexes code")
+        .arg(
+            Arg::with_name("no-canonicalize")
+                .long("no-canonicalize")
+                .help("Don't resolve symlinks in input paths, only make them absolute"),
+        )
+        .arg(
+            Arg::with_name("index")
+                .long("index")
+                .takes_value(true)
+                .help("Use a named index instead of the default one"),
+        )
+        .arg(
+            Arg::with_name("db-path")
+                .long("db-path")
+                .takes_value(true)
+                .env("TREE_TAGS_DB")
+                .conflicts_with("index")
+                .help("Path of the index database"),
+        )
+        .arg(
+            Arg::with_name("parsers-dir")
+                .long("parsers-dir")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .env("TREE_TAGS_PARSERS")
+                .help("Another directory of tree-sitter grammars, searched after the default one"),
+        )
+        .arg(
+            Arg::with_name("compiled-parsers-dir")
+                .long("compiled-parsers-dir")
+                .takes_value(true)
+                .env("TREE_TAGS_COMPILED_PARSERS")
+                .help("Directory in which to store compiled parsers"),
+        )
+        .arg(
+            Arg::with_name("one-based")
+                .long("one-based")
+                .help("Number lines starting from 1 in positions, as most editors do"),
+        )
+        .arg(
+            Arg::with_name("one-based-columns")
+                .long("one-based-columns")
+                .requires("one-based")
+                .help("Also number columns starting from 1"),
+        )
+        .arg(
+            Arg::with_name("framing")
+                .long("framing")
+                .takes_value(true)
+                .possible_values(&["ndjson", "null"])
+                .help("How to terminate each JSON response (default ndjson)"),
+        )
+        .arg(
+            Arg::with_name("parser-abi-version")
+                .long("parser-abi-version")
+                .takes_value(true)
+                .global(true)
+                .help("Accepted parser ABI version, or range of versions (e.g. 8-9)"),
+        )
+        .arg(
+            Arg::with_name("ignore-parser-abi-version")
+                .long("ignore-parser-abi-version")
+                .global(true)
+                .help("Load grammars even if their ABI version is outside the accepted range"),
+        )
         .subcommand(
             SubCommand::with_name("index")
                 .about("Index a directory of source code")
-                .arg(Arg::with_name("path").index(1)),
+                .arg(Arg::with_name("path").index(1))
+                .arg(
+                    Arg::with_name("skip-locals")
+                        .long("skip-locals")
+                        .help("Only record module-level definitions and references"),
+                ).arg(
+                    Arg::with_name("locals-max-file-size")
+                        .long("locals-max-file-size")
+                        .takes_value(true)
+                        .help("Only record local definitions in files up to this many bytes"),
+                ).arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Reindex files even if they haven't changed since they were indexed"),
+                ).arg(
+                    Arg::with_name("atomic")
+                        .long("atomic")
+                        .help("Build a fresh index in a temporary database and swap it in"),
+                ).arg(
+                    Arg::with_name("git-ignore")
+                        .long("git-ignore")
+                        .takes_value(true)
+                        .possible_values(&["true", "false"])
+                        .help("Whether to respect .gitignore files"),
+                ).arg(
+                    Arg::with_name("git-global")
+                        .long("git-global")
+                        .takes_value(true)
+                        .possible_values(&["true", "false"])
+                        .help("Whether to respect the global gitignore file"),
+                ).arg(
+                    Arg::with_name("ignore")
+                        .long("ignore")
+                        .takes_value(true)
+                        .possible_values(&["true", "false"])
+                        .help("Whether to respect .ignore files"),
+                ).arg(
+                    Arg::with_name("max-node-count")
+                        .long("max-node-count")
+                        .takes_value(true)
+                        .help("Skip files with more syntax nodes than this (default 1000000, 0 for no limit)"),
+                ).arg(
+                    Arg::with_name("max-file-size")
+                        .long("max-file-size")
+                        .takes_value(true)
+                        .help("Skip files over this many bytes (default 2MiB, 0 for no limit)"),
+                ).arg(
+                    Arg::with_name("fast-import")
+                        .long("fast-import")
+                        .help(
+                            "Don't wait for writes to reach the disk. Faster, but a crash \
+                             during indexing can corrupt the index, requiring a full rebuild",
+                        ),
+                ).arg(
+                    Arg::with_name("compact-references")
+                        .long("compact-references")
+                        .help(
+                            "Store each name that is referenced in a file once, rather than \
+                             once per reference. The index keeps this layout from then on",
+                        ),
+                ).arg(
+                    Arg::with_name("exclude-ext")
+                        .long("exclude-ext")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Don't index files with this extension"),
+                ).arg(
+                    Arg::with_name("skip-generated")
+                        .long("skip-generated")
+                        .help("Skip files whose first lines contain a generated-code marker"),
+                ).arg(
+                    Arg::with_name("store-lines")
+                        .long("store-lines")
+                        .help("Store the source line of each definition in the index"),
+                ).arg(
+                    Arg::with_name("index-docs")
+                        .long("index-docs")
+                        .help("Record documentation comments so they can be searched"),
+                ).arg(
+                    Arg::with_name("follow-symlinks")
+                        .long("follow-symlinks")
+                        .help("Index files within symlinked directories"),
+                ).arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .takes_value(true)
+                        .help("Number of files to index in parallel (default: number of CPUs)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("index-git-rev")
+                .about("Index a file's contents as of a git revision")
+                .arg(Arg::with_name("rev").index(1).required(true))
+                .arg(Arg::with_name("path").index(2).required(true)),
+        ).subcommand(
+            SubCommand::with_name("index-stdin")
+                .about("Index source code read from stdin, such as an unsaved editor buffer")
+                .arg(
+                    Arg::with_name("language")
+                        .long("language")
+                        .takes_value(true)
+                        .required_unless_one(&["extension", "path"])
+                        .conflicts_with("extension")
+                        .help("Name of the grammar with which to parse the source"),
+                ).arg(
+                    Arg::with_name("extension")
+                        .long("extension")
+                        .takes_value(true)
+                        .help("Parse the source as a file with this extension"),
+                ).arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("Path under which to index the source (default: <stdin>)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("reindex-language")
+                .about("Re-index only the files of one language in a directory")
+                .arg(Arg::with_name("language").index(1).required(true))
+                .arg(Arg::with_name("path").index(2)),
+        ).subcommand(
+            SubCommand::with_name("sync")
+                .about("Bring the index up to date with the files in a directory")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("watch")
+                .about("Sync the index with a directory, then keep it in sync as files change")
+                .arg(Arg::with_name("path").index(1).required(true)),
         ).subcommand(
             SubCommand::with_name("clear-index")
                 .about("Clear the index for a directory of source code")
                 .arg(Arg::with_name("path").index(1)),
+        ).subcommand(
+            SubCommand::with_name("remove")
+                .about("Remove a single file from the index")
+                .arg(Arg::with_name("path").index(1).required(true)),
         ).subcommand(
             SubCommand::with_name("find-definition")
                 .about("Find the definition of a symbol")
                 .arg(Arg::with_name("path").index(1).required(true))
-                .arg(Arg::with_name("line").index(2).required(true))
-                .arg(Arg::with_name("column").index(3).required(true)),
+                .arg(Arg::with_name("line").index(2).required_unless("offset"))
+                .arg(Arg::with_name("column").index(3).required_unless("offset"))
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .conflicts_with_all(&["line", "column"])
+                        .help("Byte offset of the position, instead of a line and column"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("The maximum number of definitions to print (default 50)"),
+                ).arg(
+                    Arg::with_name("first")
+                        .long("first")
+                        .help("Print only the best definition, exiting with status 1 if none"),
+                ).arg(
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .takes_value(true)
+                        .possible_values(&["location", "kind", "name"])
+                        .conflicts_with("first")
+                        .help("Sort the definitions instead of printing the best matches first"),
+                ).arg(
+                    Arg::with_name("show-line")
+                        .long("show-line")
+                        .help("Print the stored source line of each definition"),
+                ).arg(
+                    Arg::with_name("also-index")
+                        .long("also-index")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Another named index to search for definitions as well"),
+                ),
         ).subcommand(
             SubCommand::with_name("find-usages")
                 .about("Find usages of a symbol")
                 .arg(Arg::with_name("path").index(1).required(true))
-                .arg(Arg::with_name("line").index(2).required(true))
-                .arg(Arg::with_name("column").index(3).required(true)),
+                .arg(Arg::with_name("line").index(2).required_unless("offset"))
+                .arg(Arg::with_name("column").index(3).required_unless("offset"))
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .conflicts_with_all(&["line", "column"])
+                        .help("Byte offset of the position, instead of a line and column"),
+                )
+                .arg(
+                    Arg::with_name("within")
+                        .long("within")
+                        .takes_value(true)
+                        .help("Only report usages in files under this path"),
+                ).arg(
+                    Arg::with_name("with-text")
+                        .long("with-text")
+                        .help("Print the source text of each usage, read from its file"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("find-implementations")
+                .about("Find the implementations of an interface or of one of its members")
+                .arg(Arg::with_name("path").index(1).required(true))
+                .arg(Arg::with_name("line").index(2).required_unless("offset"))
+                .arg(Arg::with_name("column").index(3).required_unless("offset"))
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .conflicts_with_all(&["line", "column"])
+                        .help("Byte offset of the position, instead of a line and column"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("serve")
+                .about("Answer definition and usage queries read from stdin, one per line"),
+        ).subcommand(
+            SubCommand::with_name("symbols-in-scope")
+                .about("List the symbols that are visible at a position")
+                .arg(Arg::with_name("path").index(1).required(true))
+                .arg(Arg::with_name("line").index(2).required_unless("offset"))
+                .arg(Arg::with_name("column").index(3).required_unless("offset"))
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .conflicts_with_all(&["line", "column"])
+                        .help("Byte offset of the position, instead of a line and column"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("enclosing")
+                .about("List the definitions that contain a position, from the outermost inward")
+                .arg(Arg::with_name("path").index(1).required(true))
+                .arg(Arg::with_name("line").index(2).required_unless("offset"))
+                .arg(Arg::with_name("column").index(3).required_unless("offset"))
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .conflicts_with_all(&["line", "column"])
+                        .help("Byte offset of the position, instead of a line and column"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("locals")
+                .about("Print the local definitions in a file and their references, as JSON")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("unresolved")
+                .about("List references that don't match any indexed definition")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .help("Only list references in files within this directory"),
+                ).arg(
+                    Arg::with_name("include-builtins")
+                        .long("include-builtins")
+                        .help("Also list references to the language's configured builtins"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("find-symbol")
+                .about("Find definitions by name")
+                .arg(Arg::with_name("name").index(1).required(true))
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .help("Also find definitions whose names start with the given name"),
+                ).arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("The maximum number of definitions to print (default 100)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("goto")
+                .about("Find the definitions named by a module path, like a::b::c")
+                .arg(Arg::with_name("symbol-path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare the definitions in this index with another index")
+                .arg(Arg::with_name("other-db").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("export-ctags")
+                .about("Write the indexed definitions to a ctags file")
+                .arg(Arg::with_name("output").index(1)),
+        ).subcommand(
+            SubCommand::with_name("resolve")
+                .about("Find the most likely definition of a symbol name")
+                .arg(Arg::with_name("name").index(1).required(true))
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("The number of ranked candidates to print (default 1)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("add-grammar")
+                .about("Register a grammar directory and compile its parser")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("remove-grammar")
+                .about("Unregister a grammar directory")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("debug-file")
+                .about("Print the syntax nodes of a file that matched indexing properties")
+                .arg(Arg::with_name("path").index(1).required(true))
+                .arg(
+                    Arg::with_name("all-nodes")
+                        .long("all-nodes")
+                        .help("Print every node, including ones without any properties"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("profile-file")
+                .about("Index a single file, reporting how long each step takes")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("validate-grammar")
+                .about("Report which tagging properties a grammar assigns in a sample file")
+                .arg(Arg::with_name("language").index(1).required(true))
+                .arg(
+                    Arg::with_name("sample-file")
+                        .index(2)
+                        .help("Defaults to the first file in the grammar's examples directory"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("explain")
+                .about("Explain whether and how a file would be indexed")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("resolve-file")
+                .about("Print the id under which a file is indexed")
+                .arg(Arg::with_name("path").index(1).required(true)),
+        ).subcommand(
+            SubCommand::with_name("repair")
+                .about("Remove orphaned rows from the index"),
+        ).subcommand(
+            SubCommand::with_name("stats")
+                .about("Report the contents of the index")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .help("Only count files within this directory"),
+                ).arg(
+                    Arg::with_name("files")
+                        .long("files")
+                        .help("Also list each indexed file's content hash and syntax error count"),
+                ).arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the statistics as a JSON object"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("hotspots")
+                .about("List the definitions whose names are referenced most often")
+                .arg(
+                    Arg::with_name("module")
+                        .long("module")
+                        .takes_value(true)
+                        .help("Only list definitions within this module (e.g. a::b)"),
+                ).arg(
+                    Arg::with_name("kind")
+                        .long("kind")
+                        .takes_value(true)
+                        .help("Only list definitions of this kind"),
+                ).arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("The maximum number of definitions to print (default 50)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("search-docs")
+                .about("Search indexed documentation comments for some text")
+                .arg(Arg::with_name("query").index(1).required(true))
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("The maximum number of comments to print (default 100)"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("workspace-symbols")
+                .about("Search definitions by name, with their containing modules")
+                .arg(Arg::with_name("query").index(1).required(true))
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("Maximum number of symbols to print (default 100)"),
+                ).arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .help("Print LSP SymbolInformation objects when set to json"),
+                ),
         ).get_matches();
 
-    let config_path = dirs::home_dir().unwrap().join(".config/tree-tags");
-    let db_path = config_path.join("db.sqlite");
-    let parsers_path = config_path.join("parsers");
-    let compiled_parsers_path = config_path.join("parsers-compiled");
+    let canonicalize_paths = !matches.is_present("no-canonicalize");
+    let null_framing = matches.value_of("framing") == Some("null");
+    let position_base = Point {
+        row: if matches.is_present("one-based") { 1 } else { 0 },
+        column: if matches.is_present("one-based-columns") { 1 } else { 0 },
+    };
+    // The environment variable can contain several directories, separated
+    // like the directories in `PATH`.
+    let parsers_dirs = matches
+        .values_of_os("parsers-dir")
+        .into_iter()
+        .flatten()
+        .flat_map(std::env::split_paths)
+        .collect();
 
-    let mut store = store::Store::new(db_path)?;
-    let mut language_registry = language_registry::LanguageRegistry::new(
-        compiled_parsers_path,
-        vec![parsers_path]
-    );
-
-    store
-        .initialize()
-        .expect("Failed to initialize database schema");
+    // A project config is found by searching upward from the path that the
+    // command operates on, or from the current directory. Its settings take
+    // precedence over the global config, and command-line arguments take
+    // precedence over both.
+    let current_dir = std::env::current_dir()?;
+    let project_path = match matches.subcommand().1.and_then(|m| m.value_of("path")) {
+        Some(path) => project::normalize_path(&current_dir.join(path)),
+        None => current_dir.clone(),
+    };
+    let project_options = project::ProjectOptions {
+        config_dir: dirs::home_dir().unwrap().join(".config/tree-tags"),
+        project_path,
+        db_path: matches
+            .value_of("db-path")
+            .map(|db_path| project::normalize_path(&current_dir.join(db_path))),
+        index_name: matches.value_of("index").map(str::to_owned),
+        compiled_parsers_dir: matches.value_of("compiled-parsers-dir").map(PathBuf::from),
+        parsers_dirs,
+    };
+    let mut project = match project::Project::open(project_options) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     if let Some(matches) = matches.subcommand_matches("index") {
-        language_registry.load_parsers()?;
-        let mut crawler = crawler::DirCrawler::new(store, language_registry);
-        crawler.crawl_path(get_path_arg(matches.value_of("path").unwrap())?)?;
+        let options = crawler::CrawlOptions {
+            skip_locals: matches.is_present("skip-locals"),
+            locals_max_file_size: matches
+                .value_of("locals-max-file-size")
+                .map(|size| usize::from_str_radix(size, 10).expect("Invalid file size")),
+            abi_version_range: matches
+                .value_of("parser-abi-version")
+                .map(get_abi_version_range_arg),
+            ignore_abi_version_range: matches.is_present("ignore-parser-abi-version"),
+            disable_git_ignore: matches.value_of("git-ignore") == Some("false"),
+            disable_git_global: matches.value_of("git-global") == Some("false"),
+            disable_ignore: matches.value_of("ignore") == Some("false"),
+            max_node_count: match matches.value_of("max-node-count") {
+                Some("0") => None,
+                Some(count) => Some(usize::from_str_radix(count, 10).expect("Invalid node count")),
+                None => Some(DEFAULT_MAX_NODE_COUNT),
+            },
+            max_file_size: match matches.value_of("max-file-size") {
+                Some("0") => None,
+                Some(size) => Some(u64::from_str_radix(size, 10).expect("Invalid file size")),
+                None => Some(DEFAULT_MAX_FILE_SIZE),
+            },
+            excluded_extensions: matches
+                .values_of("exclude-ext")
+                .into_iter()
+                .flatten()
+                .map(|extension| extension.trim_start_matches('.').to_owned())
+                .collect(),
+            skip_unchanged: !matches.is_present("force"),
+            index_docs: matches.is_present("index-docs"),
+            store_source_lines: matches.is_present("store-lines"),
+            generated_markers: if matches.is_present("skip-generated") {
+                Some(
+                    RegexSet::new(project.config.generated_markers())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                )
+            } else {
+                None
+            },
+            follow_symlinks: matches.is_present("follow-symlinks"),
+            canonicalize_paths,
+            show_progress: atty::is(atty::Stream::Stderr),
+            jobs: matches
+                .value_of("jobs")
+                .map(|jobs| usize::from_str_radix(jobs, 10).expect("Invalid job count")),
+            ..project.crawl_options()?
+        };
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        let atomic = matches.is_present("atomic");
+        if matches.is_present("compact-references") {
+            project.store.compact_references()?;
+        }
+        project.index(path, options, atomic, matches.is_present("fast-import"))?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("index-git-rev") {
+        project.language_registry.load_parsers()?;
+        let rev = matches.value_of("rev").unwrap();
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        let source_code = read_git_blob(rev, &path)?;
+
+        // Record the blob's contents under a separate path, so that they
+        // don't replace the indexed contents of the working copy.
+        let indexed_path = PathBuf::from(format!("{}:{}", rev, path.display()));
+        let options = project.crawl_options()?;
+        let mut crawler = project.crawler(options);
+        crawler.crawl_buffer(&path, &indexed_path, &source_code)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("index-stdin") {
+        project.language_registry.load_parsers()?;
+        let mut source_code = Vec::new();
+        io::stdin().read_to_end(&mut source_code)?;
+        let source_code = crawler::decode_source(source_code);
+
+        // The buffer may not have been saved yet, so its path can't always
+        // be canonicalized.
+        let path_arg = matches.value_of("path").unwrap_or("<stdin>");
+        let indexed_path =
+            get_path_arg(path_arg, canonicalize_paths && Path::new(path_arg).exists())?;
+        let options = crawler::CrawlOptions {
+            excluded_paths: None,
+            ..project.crawl_options()?
+        };
+        let mut crawler = project.crawler(options);
+        if let Some(language_name) = matches.value_of("language") {
+            crawler.crawl_buffer_with_language(language_name, &indexed_path, &source_code)?;
+        } else if let Some(extension) = matches.value_of("extension") {
+            let path = PathBuf::from(format!("stdin.{}", extension.trim_start_matches('.')));
+            crawler.crawl_buffer(&path, &indexed_path, &source_code)?;
+        } else {
+            crawler.crawl_buffer(&indexed_path, &indexed_path, &source_code)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("reindex-language") {
+        let language_name = matches.value_of("language").unwrap();
+        project.language_registry.load_parsers()?;
+        if !project.language_registry.has_language_name(language_name) {
+            eprintln!("Unknown language: {}", language_name);
+            return Ok(());
+        }
+        let options = crawler::CrawlOptions {
+            language_name: Some(language_name.to_owned()),
+            abi_version_range: matches
+                .value_of("parser-abi-version")
+                .map(get_abi_version_range_arg),
+            ignore_abi_version_range: matches.is_present("ignore-parser-abi-version"),
+            ..project.crawl_options()?
+        };
+        let mut crawler = project.crawler(options);
+        let path = get_path_arg(matches.value_of("path").unwrap_or("."), canonicalize_paths)?;
+        crawler.crawl_path(path)?;
+        return Ok(());
+    }
+
+    let sync_matches = matches
+        .subcommand_matches("sync")
+        .map(|matches| (matches, false))
+        .or(matches.subcommand_matches("watch").map(|matches| (matches, true)));
+    if let Some((matches, keep_watching)) = sync_matches {
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        let options = crawler::CrawlOptions {
+            max_node_count: Some(DEFAULT_MAX_NODE_COUNT),
+            max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+            skip_unchanged: true,
+            show_progress: atty::is(atty::Stream::Stderr),
+            ..project.crawl_options()?
+        };
+        let mut crawler = project.sync(&path, options)?;
+        if keep_watching {
+            watch::watch(&mut crawler, &path)?;
+        }
         return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("clear-index") {
-        store.delete_files(&get_path_arg(matches.value_of("path").unwrap())?)?;
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        project.store.delete_files(&path)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("remove") {
+        // The file has usually been deleted already, so its path can't
+        // always be canonicalized.
+        let path_arg = matches.value_of("path").unwrap();
+        let path = get_path_arg(path_arg, canonicalize_paths && Path::new(path_arg).exists())?;
+        if !project.store.delete_file(&path)? {
+            eprintln!("{} is not indexed", path.display());
+            std::process::exit(1);
+        }
         return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("find-definition") {
-        let path = get_path_arg(matches.value_of("path").expect("Missing path"))?;
-        let line_arg = matches.value_of("line").expect("Missing line");
-        let column_arg = matches.value_of("column").expect("Missing column");
-        let position = Point {
-            row: u32::from_str_radix(line_arg, 10).expect("Invalid row"),
-            column: u32::from_str_radix(column_arg, 10).expect("Invalid column"),
+        let path = get_path_arg(
+            matches.value_of("path").expect("Missing path"),
+            canonicalize_paths,
+        )?;
+        let position = get_position_arg(matches, &path, position_base)?;
+        let limit = matches
+            .value_of("limit")
+            .map_or(50, |limit| usize::from_str_radix(limit, 10).expect("Invalid limit"));
+        let other_index_names = matches
+            .values_of("also-index")
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let mut search = project.find_definition(&path, position, limit, &other_index_names)?;
+        if matches.is_present("first") {
+            if search.definitions.is_empty() {
+                std::process::exit(1);
+            }
+            if search.definitions.len() > 1 {
+                eprintln!(
+                    "{}{} other definitions",
+                    search.definitions.len() - 1,
+                    if search.has_more { "+" } else { "" }
+                );
+            }
+            search.definitions.truncate(1);
+        }
+        if let Some(order) = matches.value_of("sort") {
+            project.store.sort_definitions(&mut search.definitions, order)?;
+        }
+        // When other indexes are searched too, every definition is labeled
+        // with the index that it came from, and this index's definitions
+        // are printed first.
+        let index_label = if other_index_names.is_empty() {
+            None
+        } else {
+            Some(
+                matches
+                    .value_of("index")
+                    .or(project.config.index.as_ref().map(String::as_str))
+                    .unwrap_or("default"),
+            )
         };
-        for (path, position, length) in store.find_definition(&path, position)? {
+
+        // Local definitions, and definitions whose kind wasn't recorded, are
+        // printed with a kind of `-`.
+        for definition in search.definitions.iter() {
+            let mut kind = definition.kind.clone().unwrap_or_else(|| "-".to_owned());
+            if let Some(index_label) = index_label {
+                kind = format!("{} {}", kind, index_label);
+            }
+            print!(
+                "{} {} {} {} {}",
+                definition.path.display(),
+                definition.position.row + position_base.row,
+                definition.position.column + position_base.column,
+                definition.length,
+                kind
+            );
+            if matches.is_present("show-line") {
+                let source_line = project
+                    .store
+                    .definition_source_line(&definition.path, definition.position)?;
+                print!(" {}", source_line.unwrap_or_default().trim());
+            }
+            println!();
+        }
+        if search.has_more && !matches.is_present("first") {
+            println!("more");
+        }
+        for (index_name, definition) in search.other_definitions.iter() {
             println!(
+                "{} {} {} {} {} {}",
+                definition.path.display(),
+                definition.position.row + position_base.row,
+                definition.position.column + position_base.column,
+                definition.length,
+                definition.kind.as_ref().map_or("-", String::as_str),
+                index_name
+            );
+        }
+        if search.other_has_more {
+            println!("more");
+        }
+        if let Some(builtin) = search.builtin {
+            println!("builtin {}", builtin);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("find-usages") {
+        let path = get_path_arg(
+            matches.value_of("path").expect("Missing path"),
+            canonicalize_paths,
+        )?;
+        let position = get_position_arg(matches, &path, position_base)?;
+        let within = match matches.value_of("within") {
+            Some(within) => Some(get_path_arg(within, canonicalize_paths)?),
+            None => None,
+        };
+        let within = within.as_ref().map(|within| within.as_path());
+        let with_text = matches.is_present("with-text");
+        let mut source_files = BTreeMap::new();
+        for usage in project.store.find_usages(&path, position, within)? {
+            print!(
                 "{} {} {} {}",
+                usage.path.display(),
+                usage.position.row + position_base.row,
+                usage.position.column + position_base.column,
+                usage.length
+            );
+
+            // Files may have changed or been removed since they were indexed,
+            // in which case the text is left out.
+            if with_text {
+                let source_code = source_files
+                    .entry(usage.path.clone())
+                    .or_insert_with(|| fs::read_to_string(&usage.path).ok());
+                let text = source_code.as_ref().and_then(|source_code| {
+                    source_text_at(source_code, usage.position, usage.length)
+                });
+                if let Some(text) = text {
+                    print!(" {}", text);
+                }
+            }
+            println!();
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("find-implementations") {
+        let path = get_path_arg(
+            matches.value_of("path").expect("Missing path"),
+            canonicalize_paths,
+        )?;
+        let position = get_position_arg(matches, &path, position_base)?;
+        let implementations = project.store.find_implementations(&path, position)?;
+        for (path, position, length, interface) in implementations {
+            println!(
+                "{} {} {} {} {}",
                 path.display(),
-                position.row,
-                position.column,
+                position.row + position_base.row,
+                position.column + position_base.column,
+                length,
+                interface
+            );
+        }
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("serve").is_some() {
+        server::serve(&mut project.store, canonicalize_paths, null_framing)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("symbols-in-scope") {
+        let path = get_path_arg(
+            matches.value_of("path").expect("Missing path"),
+            canonicalize_paths,
+        )?;
+        let position = get_position_arg(matches, &path, position_base)?;
+        for (name, position, kind) in project.store.symbols_in_scope(&path, position)? {
+            println!(
+                "{} {} {} {}",
+                name,
+                position.row + position_base.row,
+                position.column + position_base.column,
+                kind.as_ref().map_or("local", |kind| kind.as_str())
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("enclosing") {
+        let path = get_path_arg(
+            matches.value_of("path").expect("Missing path"),
+            canonicalize_paths,
+        )?;
+        let position = get_position_arg(matches, &path, position_base)?;
+        let definitions = project.store.enclosing_definitions(&path, position)?;
+        for (name, kind, position, length) in definitions {
+            println!(
+                "{} {} {} {} {}",
+                name,
+                kind.as_ref().map_or("-", String::as_str),
+                position.row + position_base.row,
+                position.column + position_base.column,
                 length
             );
         }
         return Ok(());
     }
 
+    if let Some(matches) = matches.subcommand_matches("locals") {
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        let locals = project
+            .store
+            .locals(&path)?
+            .into_iter()
+            .map(|(name, position, length, references)| {
+                let references = references
+                    .into_iter()
+                    .map(|(position, length)| {
+                        serde_json::json!({
+                            "row": position.row + position_base.row,
+                            "column": position.column + position_base.column,
+                            "length": length,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                serde_json::json!({
+                    "name": name,
+                    "row": position.row + position_base.row,
+                    "column": position.column + position_base.column,
+                    "length": length,
+                    "references": references,
+                })
+            })
+            .collect::<Vec<_>>();
+        print_json(&serde_json::Value::Array(locals), null_framing);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("unresolved") {
+        let path = match matches.value_of("path") {
+            Some(path) => Some(get_path_arg(path, canonicalize_paths)?),
+            None => None,
+        };
+        let include_builtins = matches.is_present("include-builtins");
+        let references = project.store.unresolved_references(path.as_ref().map(|p| p.as_path()))?;
+        for (path, position, name, language) in references {
+            let is_builtin = language
+                .as_ref()
+                .and_then(|language| project.config.language(language))
+                .map_or(false, |language_config| language_config.builtins.contains(&name));
+            if is_builtin && !include_builtins {
+                continue;
+            }
+            println!(
+                "{} {} {} {}",
+                path.display(),
+                position.row + position_base.row,
+                position.column + position_base.column,
+                name
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("find-symbol") {
+        let limit = matches
+            .value_of("limit")
+            .map_or(100, |limit| usize::from_str_radix(limit, 10).expect("Invalid limit"));
+        let name = matches.value_of("name").unwrap();
+        for definition in project.store.find_symbol(name, matches.is_present("prefix"), limit)? {
+            let module_path = definition.module_path.unwrap_or_default();
+            println!(
+                "{} {} {} {} {} {}",
+                definition.path.display(),
+                definition.position.row + position_base.row,
+                definition.position.column + position_base.column,
+                definition.length,
+                definition.kind.as_ref().map_or("-", String::as_str),
+                if module_path.is_empty() { "-" } else { module_path.as_str() }
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("goto") {
+        let symbol_path = matches.value_of("symbol-path").unwrap();
+        let definitions = project.store.definitions_at_module_path(symbol_path)?;
+        if definitions.is_empty() {
+            std::process::exit(1);
+        }
+        for (path, position, length, module_path) in definitions {
+            println!(
+                "{} {} {} {} {}",
+                path.display(),
+                position.row + position_base.row,
+                position.column + position_base.column,
+                length,
+                if module_path.is_empty() { "-" } else { module_path.as_str() }
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        let other_db_path = get_path_arg(matches.value_of("other-db").unwrap(), true)?;
+        let mut other_store = store::Store::new(other_db_path)?;
+        print_index_diff(project.store.definitions()?, other_store.definitions()?);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("export-ctags") {
+        project.language_registry.load_parsers()?;
+        let mut output = File::create(matches.value_of("output").unwrap_or("tags"))?;
+        let definitions = project.store.definitions()?;
+        ctags::write_tags(&mut output, definitions, &project.language_registry, &project.config)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("resolve") {
+        let name = matches.value_of("name").unwrap();
+        let limit = matches
+            .value_of("limit")
+            .map_or(1, |limit| usize::from_str_radix(limit, 10).expect("Invalid limit"));
+        for definition in project.store.resolve_name(name, limit)? {
+            println!(
+                "{} {} {} {} {}",
+                definition.path.display(),
+                definition.position.row + position_base.row,
+                definition.position.column + position_base.column,
+                definition.length,
+                definition.kind.as_ref().map_or("-", String::as_str)
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("add-grammar") {
+        let grammar_path = get_path_arg(matches.value_of("path").unwrap(), true)?;
+        if !language_registry::is_grammar_path(&grammar_path) {
+            eprintln!(
+                "{} does not contain a package.json or tree-sitter.json and src/parser.c",
+                grammar_path.display()
+            );
+            return Ok(());
+        }
+        let name = project.language_registry.load_grammar(&grammar_path)?;
+        if config::add_grammar(&project.config_file_path(), &grammar_path)? {
+            println!("Added grammar {}", name);
+        } else {
+            println!("Grammar {} was already registered", name);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("remove-grammar") {
+        let path_arg = matches.value_of("path").unwrap();
+        let grammar_path = match get_path_arg(path_arg, true) {
+            Ok(path) => path,
+            Err(_) => std::env::current_dir()?.join(path_arg),
+        };
+        if !config::remove_grammar(&project.config_file_path(), &grammar_path)? {
+            eprintln!("{} is not a registered grammar", grammar_path.display());
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("debug-file") {
+        project.language_registry.load_parsers()?;
+        let mut crawler = project.crawler(Default::default());
+        crawler.debug_file(
+            &get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?,
+            matches.is_present("all-nodes"),
+        )?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("profile-file") {
+        project.language_registry.load_parsers()?;
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        let mut crawler = project.crawler(Default::default());
+        match crawler.profile_file(&path)? {
+            Ok(profile) => {
+                println!("parse:       {:?}", profile.parse_time);
+                println!("nodes:       {}", profile.node_count);
+                println!("crawl:       {:?}", profile.crawl_time);
+                println!("commit:      {:?}", profile.commit_time);
+                println!("definitions: {}", profile.definition_count);
+                println!("references:  {}", profile.reference_count);
+            }
+            Err(skip_reason) => {
+                eprintln!("{} was not indexed: {}", path.display(), skip_reason)
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate-grammar") {
+        project.language_registry.load_parsers()?;
+        let language_name = matches.value_of("language").unwrap();
+        let sample_path = match matches.value_of("sample-file") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => project
+                .language_registry
+                .language_path(language_name)
+                .and_then(|path| first_example_path(path)),
+        };
+        let sample_path = match sample_path {
+            Some(path) => path,
+            None => {
+                eprintln!("No sample file given, and {} has no examples", language_name);
+                return Ok(());
+            }
+        };
+        let mut crawler = project.crawler(Default::default());
+        crawler.validate_grammar(language_name, &sample_path)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("explain") {
+        project.language_registry.load_parsers()?;
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        let options = crawler::CrawlOptions {
+            max_node_count: Some(DEFAULT_MAX_NODE_COUNT),
+            max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+            ..Default::default()
+        };
+        let mut crawler = project.crawler(options);
+        println!("{}: {}", path.display(), crawler.explain(&path)?);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("resolve-file") {
+        let path = get_path_arg(matches.value_of("path").unwrap(), canonicalize_paths)?;
+        println!("{} {}", project.store.file_id(&path)?, path.display());
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("repair").is_some() {
+        let orphan_count = project.store.repair()?;
+        println!("Removed {} orphaned rows", orphan_count);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        let path = match matches.value_of("path") {
+            Some(path) => Some(get_path_arg(path, canonicalize_paths)?),
+            None => None,
+        };
+        let stats = project.store.stats(path.as_ref().map(|p| p.as_path()))?;
+        let file_summaries = if matches.is_present("files") {
+            project.store.file_summaries(path.as_ref().map(|p| p.as_path()))?
+        } else {
+            Vec::new()
+        };
+        if matches.is_present("json") {
+            let mut languages = serde_json::Map::new();
+            for stats in stats.languages.iter() {
+                let mut object = serde_json::to_value(stats).unwrap();
+                object["definitions_per_file"] = stats.definitions_per_file().into();
+                languages.insert(
+                    stats.language.clone().unwrap_or("unknown".to_owned()),
+                    object,
+                );
+            }
+            let mut object = serde_json::json!({
+                "files": stats.files,
+                "files_with_errors": stats.files_with_errors,
+                "definitions": stats.definitions,
+                "references": stats.references,
+                "reference_names": stats.reference_names,
+                "compact_references": stats.compact_references,
+                "local_definitions": stats.local_definitions,
+                "local_references": stats.local_references,
+                "kinds": stats.kinds,
+                "languages": languages,
+            });
+            if matches.is_present("files") {
+                let mut files = serde_json::Map::new();
+                for (path, content_hash, error_count) in file_summaries.iter() {
+                    files.insert(
+                        path.to_string_lossy().into_owned(),
+                        serde_json::json!({
+                            "content_hash": content_hash.map(format_hash),
+                            "error_count": error_count,
+                        }),
+                    );
+                }
+                object["indexed_files"] = files.into();
+            }
+            print_json(&object, null_framing);
+        } else {
+            println!("files:             {}", stats.files);
+            println!("files with errors: {}", stats.files_with_errors);
+            println!("definitions:       {}", stats.definitions);
+            println!(
+                "references:        {} ({} distinct names, counted separately in each file)",
+                stats.references, stats.reference_names
+            );
+            let reference_storage = if stats.compact_references {
+                "one row per name in each file"
+            } else {
+                "one row per reference"
+            };
+            println!("reference storage: {}", reference_storage);
+            println!("local definitions: {}", stats.local_definitions);
+            println!("local references:  {}", stats.local_references);
+            println!();
+            println!(
+                "{:<16} {:>8} {:>12} {:>12} {:>10}",
+                "language", "files", "definitions", "references", "defs/file"
+            );
+            for stats in stats.languages.iter() {
+                println!(
+                    "{:<16} {:>8} {:>12} {:>12} {:>10.1}",
+                    stats.language.as_ref().map_or("unknown", |l| l.as_str()),
+                    stats.files,
+                    stats.definitions,
+                    stats.references,
+                    stats.definitions_per_file()
+                );
+            }
+            println!();
+            println!("{:<16} {:>12}", "kind", "definitions");
+            for (kind, count) in stats.kinds.iter() {
+                println!("{:<16} {:>12}", kind, count);
+            }
+            if !file_summaries.is_empty() {
+                println!();
+                println!("{:<16} {:>6} {}", "content hash", "errors", "path");
+                for (path, content_hash, error_count) in file_summaries.iter() {
+                    println!(
+                        "{:<16} {:>6} {}",
+                        content_hash.map_or("-".to_owned(), format_hash),
+                        error_count.map_or("-".to_owned(), |count| count.to_string()),
+                        path.display()
+                    );
+                }
+            }
+            for stats in stats.languages.iter() {
+                if stats.definitions == 0 && stats.references == 0 {
+                    if let Some(language) = stats.language.as_ref() {
+                        println!("warning: {} produced no tags", language);
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("hotspots") {
+        let limit = matches
+            .value_of("limit")
+            .map_or(50, |limit| usize::from_str_radix(limit, 10).expect("Invalid limit"));
+        let reference_counts = project.store.reference_counts(
+            matches.value_of("module"),
+            matches.value_of("kind"),
+            limit,
+        )?;
+        for (name, module_path, kind, count) in reference_counts {
+            println!("{:>8} {}", count, format_definition_key(&(module_path, name, kind)));
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("search-docs") {
+        let limit = matches
+            .value_of("limit")
+            .map_or(100, |limit| usize::from_str_radix(limit, 10).expect("Invalid limit"));
+        let query = matches.value_of("query").unwrap();
+        for (path, position, text) in project.store.search_docs(query, limit)? {
+            println!(
+                "{} {} {} {}",
+                path.display(),
+                position.row + position_base.row,
+                position.column + position_base.column,
+                text.lines().next().unwrap_or("").trim()
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("workspace-symbols") {
+        let limit = matches
+            .value_of("limit")
+            .map_or(100, |limit| usize::from_str_radix(limit, 10).expect("Invalid limit"));
+        let query = matches.value_of("query").unwrap();
+        let symbols = project.store.workspace_symbols(query, limit)?;
+        if matches.value_of("format") == Some("json") {
+            let mut source_files = BTreeMap::new();
+            let symbols = symbols
+                .iter()
+                .map(|(name, kind, module_path, path, start, end)| {
+                    let source_code = source_files
+                        .entry(path.clone())
+                        .or_insert_with(|| fs::read_to_string(path).ok())
+                        .as_ref()
+                        .map(String::as_str);
+                    serde_json::json!({
+                        "name": name,
+                        "kind": lsp_symbol_kind(kind.as_ref().map(String::as_str)),
+                        "location": {
+                            "uri": file_uri(path),
+                            "range": {
+                                "start": {
+                                    "line": start.row,
+                                    "character": utf16_column(source_code, *start),
+                                },
+                                "end": {
+                                    "line": end.row,
+                                    "character": utf16_column(source_code, *end),
+                                },
+                            },
+                        },
+                        "containerName": container_name(module_path),
+                    })
+                })
+                .collect::<Vec<_>>();
+            print_json(&serde_json::Value::Array(symbols), null_framing);
+        } else {
+            for (name, kind, module_path, path, start, _) in symbols {
+                println!(
+                    "{} {} {} {} {} {}",
+                    name,
+                    kind.as_ref().map_or("-", String::as_str),
+                    container_name(&module_path).unwrap_or("-"),
+                    path.display(),
+                    start.row + position_base.row,
+                    start.column + position_base.column
+                );
+            }
+        }
+        return Ok(());
+    }
+
     eprintln!("Unknown command");
     Ok(())
 }
 
-fn get_path_arg(arg: &str) -> io::Result<PathBuf> {
-    std::env::current_dir().and_then(|cwd| cwd.join(arg).canonicalize())
+// JSON responses are written compactly, so that each one occupies a single
+// line. With null framing they are terminated by a NUL byte instead, which
+// can never appear inside of a JSON document.
+fn print_json(value: &serde_json::Value, null_framing: bool) {
+    if null_framing {
+        print!("{}\0", value);
+        io::stdout().flush().expect("Failed to write to stdout");
+    } else {
+        println!("{}", value);
+    }
+}
+
+// Maps definition kinds onto the `SymbolKind` numbers from the Language Server
+// Protocol, falling back to `Variable` for kinds that have no counterpart.
+// Definitions whose kinds are unknown are reported as variables.
+fn lsp_symbol_kind(kind: Option<&str>) -> u32 {
+    match kind {
+        Some("file") => 1,
+        Some("module") => 2,
+        Some("namespace") => 3,
+        Some("package") => 4,
+        Some("class") => 5,
+        Some("method") => 6,
+        Some("property") => 7,
+        Some("field") => 8,
+        Some("constructor") => 9,
+        Some("enum") => 10,
+        Some("interface") | Some("trait") => 11,
+        Some("function") => 12,
+        Some("variable") => 13,
+        Some("constant") => 14,
+        Some("enum_member") | Some("enum_variant") => 22,
+        Some("struct") => 23,
+        Some("type") | Some("type_parameter") => 26,
+        _ => 13,
+    }
+}
+
+fn print_index_diff(
+    old_definitions: Vec<store::NamedDefinition>,
+    new_definitions: Vec<store::NamedDefinition>,
+) {
+    let mut old_locations = BTreeMap::new();
+    for definition in old_definitions {
+        old_locations
+            .entry((definition.module_path, definition.name, definition.kind))
+            .or_insert_with(Vec::new)
+            .push((definition.path, definition.position));
+    }
+
+    let mut new_locations = BTreeMap::new();
+    for definition in new_definitions {
+        new_locations
+            .entry((definition.module_path, definition.name, definition.kind))
+            .or_insert_with(Vec::new)
+            .push((definition.path, definition.position));
+    }
+
+    for (key, old) in old_locations.iter_mut() {
+        old.sort();
+        match new_locations.get_mut(key) {
+            None => println!("removed {}", format_definition_key(key)),
+            Some(new) => {
+                new.sort();
+                if old != new {
+                    println!("moved {}", format_definition_key(key));
+                }
+            }
+        }
+    }
+
+    for key in new_locations.keys() {
+        if !old_locations.contains_key(key) {
+            println!("added {}", format_definition_key(key));
+        }
+    }
+}
+
+fn format_definition_key((module_path, name, kind): &(String, String, Option<String>)) -> String {
+    let mut result = String::new();
+    for module_name in module_path.split_terminator('\t') {
+        result += module_name;
+        result += "::";
+    }
+    result += name;
+    if let Some(kind) = kind {
+        result += " ";
+        result += kind;
+    }
+    result
+}
+
+fn format_hash(hash: i64) -> String {
+    format!("{:016x}", hash as u64)
+}
+
+fn source_text_at(source_code: &str, position: Point, length: usize) -> Option<&str> {
+    let line = source_code
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .nth(position.row as usize)?;
+    let start = position.column as usize;
+    line.get(start..start + length)
+}
+
+// Every byte of the path other than unreserved characters and separators is
+// percent-encoded, so that paths with spaces, `%` or `#` are valid URIs.
+fn file_uri(path: &Path) -> String {
+    let mut result = String::from("file://");
+    for byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                result.push(*byte as char)
+            }
+            _ => result += &format!("%{:02X}", byte),
+        }
+    }
+    result
+}
+
+// LSP positions count columns in UTF-16 code units, whereas indexed columns
+// count bytes. If the file can't be read, or has changed so that the column
+// no longer falls on a character boundary, the byte column is used.
+fn utf16_column(source_code: Option<&str>, position: Point) -> u32 {
+    source_code
+        .and_then(|source_code| {
+            source_code
+                .trim_start_matches('\u{feff}')
+                .lines()
+                .nth(position.row as usize)
+        })
+        .and_then(|line| line.get(..position.column as usize))
+        .map_or(position.column, |prefix| prefix.encode_utf16().count() as u32)
+}
+
+// Module paths are stored with a tab after each module's name, so the
+// innermost module is the last of the tab-terminated entries.
+fn container_name(module_path: &str) -> Option<&str> {
+    module_path.split_terminator('\t').last()
+}
+
+// The base is the position that refers to the first column of the first
+// line, which is `(0, 0)` unless one-based positions were requested.
+fn get_position_arg(matches: &ArgMatches, path: &Path, base: Point) -> io::Result<Point> {
+    if let Some(offset_arg) = matches.value_of("offset") {
+        let offset = usize::from_str_radix(offset_arg, 10).expect("Invalid offset");
+        return position_for_offset(&fs::read(path)?, offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Offset {} is past the end of {}", offset, path.display()),
+            )
+        });
+    }
+
+    let line_arg = matches.value_of("line").expect("Missing line");
+    let column_arg = matches.value_of("column").expect("Missing column");
+    let row = u32::from_str_radix(line_arg, 10).expect("Invalid row");
+    let column = u32::from_str_radix(column_arg, 10).expect("Invalid column");
+    Ok(Point {
+        row: row.checked_sub(base.row).expect("Invalid row"),
+        column: column.checked_sub(base.column).expect("Invalid column"),
+    })
+}
+
+// Positions are indexed with zero-based rows and byte columns. A `\r\n` is a
+// single line break: the `\r` counts as the last byte of its line, and an
+// offset of the `\n` refers to the same position. A byte order mark at the
+// start of the file isn't counted, since it isn't parsed.
+fn position_for_offset(source_code: &[u8], mut offset: usize) -> Option<Point> {
+    if offset > source_code.len() {
+        return None;
+    }
+    if offset > 0 && source_code.get(offset) == Some(&b'\n') && source_code[offset - 1] == b'\r' {
+        offset -= 1;
+    }
+    let text = &source_code[..offset];
+    let row = text.iter().filter(|b| **b == b'\n').count();
+    let column = match text.iter().rposition(|b| *b == b'\n') {
+        Some(newline_index) => offset - newline_index - 1,
+        None if source_code.starts_with(UTF8_BYTE_ORDER_MARK) => {
+            offset.saturating_sub(UTF8_BYTE_ORDER_MARK.len())
+        }
+        None => offset,
+    };
+    Some(Point::new(row as u32, column as u32))
+}
+
+fn get_abi_version_range_arg(arg: &str) -> (usize, usize) {
+    let mut bounds = arg
+        .splitn(2, '-')
+        .map(|bound| usize::from_str_radix(bound, 10).expect("Invalid ABI version"));
+    let min_version = bounds.next().unwrap();
+    let max_version = bounds.next().unwrap_or(min_version);
+    (min_version, max_version)
+}
+
+fn first_example_path(grammar_path: &Path) -> Option<PathBuf> {
+    let mut paths = fs::read_dir(grammar_path.join("examples"))
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.into_iter().next()
+}
+
+// Run git from the file's own directory, so that the path can be given
+// relative to it without first finding the root of the repository.
+fn read_git_blob(rev: &str, path: &Path) -> io::Result<String> {
+    let (dir, file_name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(file_name)) => (dir, file_name),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path")),
+    };
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("show")
+        .arg(format!("{}:./{}", rev, file_name.to_string_lossy()))
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(crawler::decode_source(output.stdout))
+}
+
+fn get_path_arg(arg: &str, canonicalize: bool) -> io::Result<PathBuf> {
+    let path = std::env::current_dir()?.join(arg);
+    if canonicalize {
+        path.canonicalize()
+    } else {
+        Ok(project::normalize_path(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_name() {
+        assert_eq!(container_name(""), None);
+        assert_eq!(container_name("a\t"), Some("a"));
+        assert_eq!(container_name("a\tb\t"), Some("b"));
+    }
+
+    #[test]
+    fn test_file_uri() {
+        assert_eq!(file_uri(Path::new("/a/b.js")), "file:///a/b.js");
+        assert_eq!(
+            file_uri(Path::new("/my dir/100%/#1/é.js")),
+            "file:///my%20dir/100%25/%231/%C3%A9.js"
+        );
+    }
+
+    #[test]
+    fn test_utf16_column() {
+        let source_code = "a\néa😀b\n";
+        assert_eq!(utf16_column(Some(source_code), Point::new(0, 1)), 1);
+        assert_eq!(utf16_column(Some(source_code), Point::new(1, 2)), 1);
+        assert_eq!(utf16_column(Some(source_code), Point::new(1, 7)), 4);
+        assert_eq!(utf16_column(Some(source_code), Point::new(1, 5)), 5);
+        assert_eq!(utf16_column(None, Point::new(1, 7)), 7);
+    }
+
+    #[test]
+    fn test_position_for_offset() {
+        let source_code = b"ab\ncd";
+        assert_eq!(position_for_offset(source_code, 0), Some(Point::new(0, 0)));
+        assert_eq!(position_for_offset(source_code, 2), Some(Point::new(0, 2)));
+        assert_eq!(position_for_offset(source_code, 3), Some(Point::new(1, 0)));
+        assert_eq!(position_for_offset(source_code, 5), Some(Point::new(1, 2)));
+        assert_eq!(position_for_offset(source_code, 6), None);
+    }
+
+    #[test]
+    fn test_position_for_offset_with_crlf_line_endings() {
+        let source_code = b"ab\r\ncd\r\n\r\nef";
+        assert_eq!(position_for_offset(source_code, 2), Some(Point::new(0, 2)));
+        assert_eq!(position_for_offset(source_code, 3), Some(Point::new(0, 2)));
+        assert_eq!(position_for_offset(source_code, 4), Some(Point::new(1, 0)));
+        assert_eq!(position_for_offset(source_code, 5), Some(Point::new(1, 1)));
+        assert_eq!(position_for_offset(source_code, 7), Some(Point::new(1, 2)));
+        assert_eq!(position_for_offset(source_code, 8), Some(Point::new(2, 0)));
+        assert_eq!(position_for_offset(source_code, 9), Some(Point::new(2, 0)));
+        assert_eq!(position_for_offset(source_code, 11), Some(Point::new(3, 1)));
+    }
+
+    #[test]
+    fn test_position_for_offset_with_byte_order_mark() {
+        let source_code = b"\xef\xbb\xbfab\r\ncd";
+        assert_eq!(position_for_offset(source_code, 0), Some(Point::new(0, 0)));
+        assert_eq!(position_for_offset(source_code, 3), Some(Point::new(0, 0)));
+        assert_eq!(position_for_offset(source_code, 4), Some(Point::new(0, 1)));
+        assert_eq!(position_for_offset(source_code, 6), Some(Point::new(0, 2)));
+        assert_eq!(position_for_offset(source_code, 7), Some(Point::new(1, 0)));
+    }
 }
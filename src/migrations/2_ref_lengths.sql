@@ -0,0 +1,5 @@
+-- Columns are byte offsets, so lengths must be measured in bytes too, rather
+-- than in characters as SQLite's `length` function does for text.
+ALTER TABLE refs ADD COLUMN length UNSIGNED INTEGER NOT NULL DEFAULT 0;
+
+UPDATE refs SET length = length(CAST(name AS BLOB));
@@ -0,0 +1,3 @@
+-- A hash of each file's contents, so that files whose modification times
+-- changed without their contents changing don't need to be re-indexed.
+ALTER TABLE files ADD COLUMN content_hash INTEGER;
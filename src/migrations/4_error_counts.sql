@@ -0,0 +1,3 @@
+-- The number of ERROR nodes in each file's syntax tree, which is a sign that
+-- the file was parsed with the wrong grammar or grammar version.
+ALTER TABLE files ADD COLUMN error_count INTEGER;
@@ -0,0 +1,5 @@
+-- Positional lookups of references are already covered by the primary keys
+-- of `refs` and `local_refs`. These cover finding a reference's usages, and
+-- finding definitions by their exact names.
+CREATE INDEX IF NOT EXISTS ref_name_folds ON refs (name_fold);
+CREATE INDEX IF NOT EXISTS def_names ON defs (name);
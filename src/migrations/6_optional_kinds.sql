@@ -0,0 +1,61 @@
+-- Definitions and references whose kinds aren't known are stored with a NULL
+-- kind. SQLite can't drop a column's NOT NULL constraint, so both tables are
+-- rebuilt, along with their indexes.
+CREATE TABLE new_defs (
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  start_row UNSIGNED INTEGER NOT NULL,
+  start_column UNSIGNED INTEGER NOT NULL,
+  name_start_row UNSIGNED INTEGER NOT NULL,
+  name_start_column UNSIGNED INTEGER NOT NULL,
+  end_row UNSIGNED INTEGER NOT NULL,
+  end_column UNSIGNED INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  name_fold TEXT NOT NULL,
+  kind TEXT,
+  receiver_type TEXT,
+  module_path TEXT NOT NULL,
+  source_line TEXT,
+  PRIMARY KEY (file_id, start_row, start_column, end_row, end_column)
+);
+
+INSERT INTO new_defs
+  (
+    file_id,
+    start_row, start_column,
+    name_start_row, name_start_column,
+    end_row, end_column,
+    name, name_fold, kind, receiver_type, module_path, source_line
+  )
+SELECT
+  file_id,
+  start_row, start_column,
+  name_start_row, name_start_column,
+  end_row, end_column,
+  name, name_fold, kind, receiver_type, module_path, source_line
+FROM defs;
+
+DROP TABLE defs;
+ALTER TABLE new_defs RENAME TO defs;
+CREATE INDEX def_name_folds ON defs (name_fold);
+CREATE INDEX def_names ON defs (name);
+
+CREATE TABLE new_refs (
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  row UNSIGNED INTEGER NOT NULL,
+  column UNSIGNED INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  name_fold TEXT NOT NULL,
+  kind TEXT,
+  length UNSIGNED INTEGER NOT NULL,
+  PRIMARY KEY (file_id, row, column)
+);
+
+INSERT INTO new_refs
+  (file_id, row, column, name, name_fold, kind, length)
+SELECT
+  file_id, row, column, name, name_fold, kind, length
+FROM refs;
+
+DROP TABLE refs;
+ALTER TABLE new_refs RENAME TO refs;
+CREATE INDEX ref_name_folds ON refs (name_fold);
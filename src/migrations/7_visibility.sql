@@ -0,0 +1,4 @@
+-- The visibility of each definition, like `public` or `private`, for grammars
+-- that record it. When resolving a bare name, definitions that aren't public
+-- are ranked after ones that are.
+ALTER TABLE defs ADD COLUMN visibility TEXT;
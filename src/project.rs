@@ -0,0 +1,372 @@
+// The state that every command starts from: the global config merged with the
+// config of the project being worked on, the index, and the registry of
+// grammars. Programs that embed tree-tags can open a project to use the same
+// index and settings as the command-line tool.
+
+use crate::config::{self, Config};
+use crate::crawler::{self, CrawlOptions, DirCrawler};
+use crate::language_registry::LanguageRegistry;
+use crate::store::{self, Definition, Store};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tree_sitter::Point;
+
+pub struct ProjectOptions {
+    // The directory that contains `config.toml`, the grammars and the indexes,
+    // which is `~/.config/tree-tags` for the command-line tool.
+    pub config_dir: PathBuf,
+    // The path being indexed or queried. The project config is found by
+    // searching upward from it.
+    pub project_path: PathBuf,
+    // The database to use, instead of the named index or the default one.
+    pub db_path: Option<PathBuf>,
+    // The named index to use, instead of the one chosen by the config.
+    pub index_name: Option<String>,
+    pub compiled_parsers_dir: Option<PathBuf>,
+    // Directories containing grammars, in addition to the config directory's
+    // `parsers` directory.
+    pub parsers_dirs: Vec<PathBuf>,
+}
+
+pub struct Project {
+    pub config: Config,
+    pub config_dir: PathBuf,
+    pub db_path: PathBuf,
+    pub store: Store,
+    pub language_registry: LanguageRegistry,
+}
+
+// The definitions found for a reference, including those in other indexes.
+pub struct DefinitionSearch {
+    pub definitions: Vec<Definition>,
+    // Whether some of this index's definitions were left out.
+    pub has_more: bool,
+    // Definitions with the reference's name in the other indexes, with the
+    // names of the indexes that they came from. Like this index's
+    // definitions, there are at most `limit` of them.
+    pub other_definitions: Vec<(String, Definition)>,
+    pub other_has_more: bool,
+    // The reference's name, if nothing defines it and it is one of its
+    // language's builtins.
+    pub builtin: Option<String>,
+}
+
+impl Project {
+    pub fn open(options: ProjectOptions) -> crawler::Result<Self> {
+        let config_file_path = options.config_dir.join("config.toml");
+        let mut config = Config::load(&config_file_path)?;
+
+        // The project config's settings take precedence over the global
+        // config's.
+        if let Some(project_config_path) = config::find_project_config(&options.project_path) {
+            let project_config = Config::load(&project_config_path)?;
+            config.merge(project_config, project_config_path.parent().unwrap());
+        }
+
+        let index_name = options.index_name.as_ref().or(config.index.as_ref());
+        let db_path = match (options.db_path, index_name) {
+            (Some(db_path), _) => db_path,
+            (None, Some(index_name)) => index_db_path(&options.config_dir, index_name)?,
+            (None, None) => options.config_dir.join("db.sqlite"),
+        };
+        let compiled_parsers_path = options
+            .compiled_parsers_dir
+            .or(config.compiled_parsers_dir.clone())
+            .unwrap_or(options.config_dir.join("parsers-compiled"));
+
+        if let Some(db_dir_path) = db_path.parent() {
+            fs::create_dir_all(db_dir_path)?;
+        }
+        let mut store = Store::new(db_path.clone())?;
+        store.set_kind_priority(config.kind_priority.clone());
+        store.set_case_insensitive_languages(config.case_insensitive_languages());
+        store.initialize().map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to initialize the database: {}", e))
+        })?;
+
+        let mut parsers_paths = vec![options.config_dir.join("parsers")];
+        parsers_paths.extend(options.parsers_dirs);
+        let mut language_registry = LanguageRegistry::new(compiled_parsers_path, parsers_paths);
+        for grammar_path in config.grammars.iter() {
+            language_registry.add_grammar_path(grammar_path.clone());
+        }
+        for (pattern, language_name) in config.language_globs.iter() {
+            if let Err(e) = language_registry.add_language_glob(pattern, language_name) {
+                eprintln!("Invalid language glob {}: {}", pattern, e);
+            }
+        }
+
+        Ok(Self {
+            config,
+            config_dir: options.config_dir,
+            db_path,
+            store,
+            language_registry,
+        })
+    }
+
+    pub fn config_file_path(&self) -> PathBuf {
+        self.config_dir.join("config.toml")
+    }
+
+    // Crawl options with the settings that come from the config. The
+    // remaining options have their default values.
+    pub fn crawl_options(&self) -> io::Result<CrawlOptions> {
+        let excluded_paths = self
+            .config
+            .excluded_paths()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(CrawlOptions {
+            case_insensitive_languages: self.config.case_insensitive_languages(),
+            file_module_languages: self.config.file_module_languages(),
+            languages_without_references: self.config.languages_without_references(),
+            ignored_contexts: self.config.ignored_contexts(),
+            excluded_paths,
+            ..Default::default()
+        })
+    }
+
+    pub fn crawler(self, options: CrawlOptions) -> DirCrawler {
+        DirCrawler::new(self.store, self.language_registry, options)
+    }
+
+    // Index the files within the given path. With `atomic`, a new index is
+    // built alongside the current one and replaces it once it is complete,
+    // so that queries never see a partially built index. The new index keeps
+    // the current one's reference layout.
+    pub fn index(
+        mut self,
+        path: PathBuf,
+        options: CrawlOptions,
+        atomic: bool,
+        fast_import: bool,
+    ) -> crawler::Result<()> {
+        self.language_registry.load_parsers()?;
+        self.language_registry.compile_languages()?;
+        if atomic {
+            let compact_references = self.store.has_compact_references()?;
+            drop(self.store);
+            let temp_db_path = self.db_path.with_extension("sqlite.tmp");
+            store::remove_database(&temp_db_path)?;
+            let mut temp_store = Store::new(temp_db_path.clone())?;
+            temp_store.initialize()?;
+            if compact_references {
+                temp_store.compact_references()?;
+            }
+            temp_store.set_fast_import(fast_import)?;
+            let mut crawler = DirCrawler::new(temp_store, self.language_registry, options);
+            crawler.crawl_path(path)?;
+            drop(crawler);
+            store::replace_database(&temp_db_path, &self.db_path)?;
+        } else {
+            self.store.set_fast_import(fast_import)?;
+            let mut crawler = DirCrawler::new(self.store, self.language_registry, options);
+            crawler.crawl_path(path)?;
+        }
+        Ok(())
+    }
+
+    // Bring the index up to date with the files within the given path,
+    // removing the ones that no longer exist. Returns the crawler, so that
+    // the caller can keep watching the path for changes.
+    pub fn sync(mut self, path: &Path, options: CrawlOptions) -> crawler::Result<DirCrawler> {
+        self.language_registry.load_parsers()?;
+        self.language_registry.compile_languages()?;
+        for indexed_path in self.store.file_paths(path)? {
+            if !indexed_path.exists() {
+                self.store.delete_file(&indexed_path)?;
+            }
+        }
+        let mut crawler = self.crawler(options);
+        crawler.crawl_path(path.to_owned())?;
+        Ok(crawler)
+    }
+
+    // Opens another named index, if it exists.
+    pub fn open_index(&self, index_name: &str) -> crawler::Result<Option<Store>> {
+        let db_path = index_db_path(&self.config_dir, index_name)?;
+        if !db_path.exists() {
+            return Ok(None);
+        }
+        let mut store = Store::new(db_path)?;
+        store.set_case_insensitive_languages(self.config.case_insensitive_languages());
+        Ok(Some(store))
+    }
+
+    // Find the definitions of the reference at the given position. The other
+    // indexes are searched by name, but only if they are given, or if this
+    // index has no definitions.
+    pub fn find_definition(
+        &mut self,
+        path: &Path,
+        position: Point,
+        limit: usize,
+        other_index_names: &[&str],
+    ) -> crawler::Result<DefinitionSearch> {
+        let (definitions, has_more) = self.store.find_definition(path, position, limit)?;
+        let mut search = DefinitionSearch {
+            definitions,
+            has_more,
+            other_definitions: Vec::new(),
+            other_has_more: false,
+            builtin: None,
+        };
+        if !search.definitions.is_empty() && other_index_names.is_empty() {
+            return Ok(search);
+        }
+
+        let name = match self.store.reference_name(path, position)? {
+            Some(name) => name,
+            None => return Ok(search),
+        };
+        self.language_registry.load_parsers()?;
+        let language_config = self
+            .language_registry
+            .language_name_for_path(path)
+            .and_then(|name| self.config.language(name));
+        let fold_case = language_config.map_or(false, |config| config.case_insensitive);
+
+        for index_name in other_index_names {
+            let mut store = match self.open_index(index_name)? {
+                Some(store) => store,
+                None => {
+                    eprintln!("Unknown index: {}", index_name);
+                    continue;
+                }
+            };
+            let remaining = limit - search.other_definitions.len();
+            let (definitions, has_more) = store.definitions_named(&name, fold_case, remaining)?;
+            search.other_has_more |= has_more;
+            for definition in definitions {
+                search.other_definitions.push((index_name.to_string(), definition));
+            }
+        }
+
+        let builtins = language_config.map_or(&[][..], |config| &config.builtins[..]);
+        if search.definitions.is_empty()
+            && search.other_definitions.is_empty()
+            && builtins.contains(&name)
+        {
+            search.builtin = Some(name);
+        }
+        Ok(search)
+    }
+}
+
+// Named indexes are stored alongside each other in the config directory.
+pub fn index_db_path(config_dir: &Path, index_name: &str) -> io::Result<PathBuf> {
+    config::validate_index_name(index_name)?;
+    Ok(config_dir.join("indexes").join(index_name.to_owned() + ".sqlite"))
+}
+
+// Resolve `.` and `..` components without following symlinks.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            component => result.push(component.as_os_str()),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::DefinitionRecord;
+    use crate::test_util::TempDir;
+
+    fn options(dir: &TempDir, index_name: Option<&str>) -> ProjectOptions {
+        ProjectOptions {
+            config_dir: dir.path().join("config"),
+            project_path: dir.path().join("project"),
+            db_path: None,
+            index_name: index_name.map(|name| name.to_owned()),
+            compiled_parsers_dir: None,
+            parsers_dirs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_open_uses_the_index_named_by_the_project_config() {
+        let dir = TempDir::new();
+        let project = Project::open(options(&dir, None)).unwrap();
+        assert_eq!(project.db_path, dir.path().join("config/db.sqlite"));
+
+        // The project config's index takes precedence over the global one's.
+        dir.write("config/config.toml", "index = \"global\"\n");
+        let project = Project::open(options(&dir, None)).unwrap();
+        assert_eq!(project.db_path, dir.path().join("config/indexes/global.sqlite"));
+        dir.write("project/.tree-tags.toml", "index = \"work\"\n");
+        let project = Project::open(options(&dir, None)).unwrap();
+        assert_eq!(project.db_path, dir.path().join("config/indexes/work.sqlite"));
+        assert!(project.db_path.exists());
+
+        // An explicit index name takes precedence over the config's.
+        let project = Project::open(options(&dir, Some("home"))).unwrap();
+        assert_eq!(project.db_path, dir.path().join("config/indexes/home.sqlite"));
+        assert!(Project::open(options(&dir, Some("../home"))).is_err());
+
+        // A project config can't name an index outside of the config directory.
+        dir.write("project/.tree-tags.toml", "index = \"../../x\"\n");
+        assert!(Project::open(options(&dir, None)).is_err());
+    }
+
+    #[test]
+    fn test_find_definition_in_other_indexes() {
+        let dir = TempDir::new();
+        let path = dir.path().join("project/a.js");
+        let other_path = dir.path().join("other/b.js");
+
+        let mut other = Project::open(options(&dir, Some("other"))).unwrap();
+        let mut file = other.store.file(&other_path, "javascript", false).unwrap();
+        let position = Point::new(0, 9);
+        let definition = DefinitionRecord {
+            name: "foo",
+            name_position: position,
+            start_position: position,
+            end_position: position,
+            kind: Some("function"),
+            receiver_type: None,
+            visibility: None,
+            source_line: None,
+        };
+        file.insert_def(&definition, &[]).unwrap();
+        file.commit().unwrap();
+        drop(other);
+
+        let mut project = Project::open(options(&dir, None)).unwrap();
+        let mut file = project.store.file(&path, "javascript", false).unwrap();
+        file.insert_ref("foo", Point::new(0, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+
+        let search = project.find_definition(&path, Point::new(0, 1), 10, &[]).unwrap();
+        assert!(search.definitions.is_empty());
+        assert!(search.other_definitions.is_empty());
+
+        let search = project
+            .find_definition(&path, Point::new(0, 1), 10, &["other", "missing"])
+            .unwrap();
+        assert_eq!(search.other_definitions.len(), 1);
+        let (index_name, definition) = &search.other_definitions[0];
+        assert_eq!(index_name, "other");
+        assert_eq!((&definition.path, definition.position), (&other_path, position));
+        assert!(!search.other_has_more);
+        assert_eq!(search.builtin, None);
+
+        let search = project.find_definition(&path, Point::new(0, 1), 0, &["other"]).unwrap();
+        assert!(search.other_definitions.is_empty());
+        assert!(search.other_has_more);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(Path::new("/a/./b/../c")), PathBuf::from("/a/c"));
+        assert_eq!(normalize_path(Path::new("a/b/..")), PathBuf::from("a"));
+    }
+}
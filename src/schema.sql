@@ -1,20 +1,36 @@
-PRAGMA foreign_keys = ON;
-
 CREATE TABLE IF NOT EXISTS files (
   id INTEGER NOT NULL PRIMARY KEY,
-  path TEXT NOT NULL UNIQUE
+  path TEXT NOT NULL UNIQUE,
+  language TEXT,
+  mtime INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS scopes (
+  id INTEGER NOT NULL PRIMARY KEY,
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  parent_id INTEGER REFERENCES scopes (id) ON DELETE CASCADE,
+  kind TEXT,
+  start_row UNSIGNED INTEGER NOT NULL,
+  start_column UNSIGNED INTEGER NOT NULL,
+  end_row UNSIGNED INTEGER NOT NULL,
+  end_column UNSIGNED INTEGER NOT NULL
 );
 
 CREATE TABLE IF NOT EXISTS local_defs (
   id INTEGER NOT NULL PRIMARY KEY,
   file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  scope_id INTEGER NOT NULL REFERENCES scopes (id) ON DELETE CASCADE,
   row UNSIGNED INTEGER NOT NULL,
   column UNSIGNED INTEGER NOT NULL,
-  length UNSIGNED INTEGER NOT NULL
+  length UNSIGNED INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  is_hoisted BOOLEAN NOT NULL,
+  declared_type TEXT
 );
 
 CREATE TABLE IF NOT EXISTS local_refs (
   file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  scope_id INTEGER NOT NULL REFERENCES scopes (id) ON DELETE CASCADE,
   definition_id INTEGER NOT NULL REFERENCES local_defs (id) ON DELETE CASCADE,
   row UNSIGNED INTEGER NOT NULL,
   column UNSIGNED INTEGER NOT NULL,
@@ -31,8 +47,11 @@ CREATE TABLE IF NOT EXISTS defs (
   end_row UNSIGNED INTEGER NOT NULL,
   end_column UNSIGNED INTEGER NOT NULL,
   name TEXT NOT NULL,
+  name_fold TEXT NOT NULL,
   kind TEXT NOT NULL,
+  receiver_type TEXT,
   module_path TEXT NOT NULL,
+  source_line TEXT,
   PRIMARY KEY (file_id, start_row, start_column, end_row, end_column)
 );
 
@@ -41,8 +60,30 @@ CREATE TABLE IF NOT EXISTS refs (
   row UNSIGNED INTEGER NOT NULL,
   column UNSIGNED INTEGER NOT NULL,
   name TEXT NOT NULL,
+  name_fold TEXT NOT NULL,
   kind TEXT NOT NULL,
   PRIMARY KEY (file_id, row, column)
 );
 
+CREATE TABLE IF NOT EXISTS implementations (
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  row UNSIGNED INTEGER NOT NULL,
+  column UNSIGNED INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  interface TEXT NOT NULL,
+  PRIMARY KEY (file_id, row, column)
+);
+
+CREATE TABLE IF NOT EXISTS docs (
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  start_row UNSIGNED INTEGER NOT NULL,
+  start_column UNSIGNED INTEGER NOT NULL,
+  end_row UNSIGNED INTEGER NOT NULL,
+  end_column UNSIGNED INTEGER NOT NULL,
+  text TEXT NOT NULL,
+  PRIMARY KEY (file_id, start_row, start_column)
+);
+
 CREATE INDEX IF NOT EXISTS file_paths ON files (path);
+CREATE INDEX IF NOT EXISTS def_name_folds ON defs (name_fold);
+CREATE INDEX IF NOT EXISTS implementation_interfaces ON implementations (interface);
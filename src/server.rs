@@ -0,0 +1,109 @@
+// Answers queries read from stdin, one per line, so that editors can keep a
+// single process running instead of spawning one per query. Requests have
+// the form:
+//
+//     def <path> <row> <column>
+//     usages <path> <row> <column>
+//
+// Each request gets exactly one JSON response, either
+// `{"results": [{"path", "row", "column", "length"}, ...]}` or
+// `{"error": "<message>"}`. Definition responses also have a `more` field,
+// indicating whether any results were left out, and each of their results
+// has a `kind` and a `module`, which are null for local definitions, and a
+// `proximity`: the number of leading modules that the definition's module
+// path shares with the reference's. Results are ordered from best to worst.
+
+use serde_json::{json, Value};
+use std::io::{self, BufRead};
+use std::path::Path;
+use tree_sitter::Point;
+use tree_tags::Store;
+
+const DEFINITION_LIMIT: usize = 50;
+
+pub fn serve(store: &mut Store, canonicalize_paths: bool, null_framing: bool) -> io::Result<()> {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match handle_request(store, &line, canonicalize_paths) {
+            Ok(response) => response,
+            Err(message) => json!({ "error": message }),
+        };
+        crate::print_json(&response, null_framing);
+    }
+    Ok(())
+}
+
+fn handle_request(
+    store: &mut Store,
+    line: &str,
+    canonicalize_paths: bool,
+) -> Result<Value, String> {
+    // Split from the end, so that paths may contain spaces.
+    let mut parts = line.trim_end().rsplitn(3, ' ');
+    let column = parts.next();
+    let row = parts.next();
+    let (command, path) = match parts.next().and_then(|rest| {
+        let mut rest = rest.splitn(2, ' ');
+        Some((rest.next()?, rest.next()?))
+    }) {
+        Some(command_and_path) => command_and_path,
+        None => return Err(format!("Malformed request: {}", line)),
+    };
+
+    let position = match (row.map(str::parse), column.map(str::parse)) {
+        (Some(Ok(row)), Some(Ok(column))) => Point { row, column },
+        _ => return Err(format!("Invalid position: {}", line)),
+    };
+    let path = crate::get_path_arg(path, canonicalize_paths).map_err(|e| e.to_string())?;
+
+    match command {
+        "def" => {
+            let (definitions, has_more) = store
+                .find_definition(&path, position, DEFINITION_LIMIT)
+                .map_err(store_error_message)?;
+            let results = definitions
+                .into_iter()
+                .map(|definition| {
+                    let mut result =
+                        result_json(&definition.path, definition.position, definition.length);
+                    result["kind"] = definition.kind.into();
+                    result["module"] = definition.module_path.into();
+                    result["proximity"] = definition.proximity.into();
+                    result
+                })
+                .collect::<Vec<_>>();
+            Ok(json!({ "results": results, "more": has_more }))
+        }
+        "usages" => {
+            let usages = store
+                .find_usages(&path, position, None)
+                .map_err(store_error_message)?;
+            let results = usages
+                .into_iter()
+                .map(|usage| result_json(&usage.path, usage.position, usage.length))
+                .collect::<Vec<_>>();
+            Ok(json!({ "results": results }))
+        }
+        _ => Err(format!("Unknown command: {}", command)),
+    }
+}
+
+fn result_json(path: &Path, position: Point, length: usize) -> Value {
+    json!({
+        "path": path.to_string_lossy(),
+        "row": position.row,
+        "column": position.column,
+        "length": length,
+    })
+}
+
+fn store_error_message(error: rusqlite::Error) -> String {
+    match error {
+        rusqlite::Error::QueryReturnedNoRows => "File is not indexed".to_owned(),
+        error => error.to_string(),
+    }
+}
@@ -1,18 +1,106 @@
-use rusqlite::{self, Connection, Result, Transaction};
+use rusqlite::{self, Connection, Result, Transaction, TransactionBehavior};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsString;
+use std::fs;
+use std::io;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 use tree_sitter::Point;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct Store {
     db: Connection,
     path: PathBuf,
+    fast_import: bool,
+    synchronous_before_fast_import: Option<i64>,
+    kind_priority: Vec<String>,
+    case_insensitive_languages: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LanguageStats {
+    pub language: Option<String>,
+    pub files: i64,
+    pub files_with_errors: i64,
+    pub definitions: i64,
+    pub references: i64,
+    pub local_definitions: i64,
+    pub local_references: i64,
+}
+
+#[derive(Serialize)]
+pub struct IndexStats {
+    pub files: i64,
+    pub files_with_errors: i64,
+    pub definitions: i64,
+    pub references: i64,
+    // The number of distinct names that are referenced in each file, summed
+    // over the files. This is roughly how many rows the references would
+    // take up if they were compacted.
+    pub reference_names: i64,
+    pub compact_references: bool,
+    pub local_definitions: i64,
+    pub local_references: i64,
+    pub kinds: BTreeMap<String, i64>,
+    pub languages: Vec<LanguageStats>,
+}
+
+// A definition returned by a query. Its kind is `None` for local definitions
+// and for definitions whose kind wasn't recorded. Its module path is joined
+// with `::`, and is `None` for local definitions and for queries that don't
+// report it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Definition {
+    pub path: PathBuf,
+    pub position: Point,
+    pub length: usize,
+    pub kind: Option<String>,
+    pub module_path: Option<String>,
+    // When finding the definition of a reference, the number of leading
+    // modules that the definition's module path shares with the reference's.
+    pub proximity: usize,
+}
+
+// An occurrence of a name, such as a usage of a definition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub path: PathBuf,
+    pub position: Point,
+    pub length: usize,
+}
+
+// A definition as listed by `definitions`, for writing tags files and for
+// comparing indexes. The module path is stored with each module name followed
+// by a tab.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamedDefinition {
+    pub name: String,
+    pub module_path: String,
+    pub kind: Option<String>,
+    pub path: PathBuf,
+    pub position: Point,
+    pub source_line: Option<String>,
+}
+
+// A definition to be stored in a file. The module path that it's nested in
+// is given separately, because it is shared by all of a module's definitions.
+pub struct DefinitionRecord<'a> {
+    pub name: &'a str,
+    pub name_position: Point,
+    pub start_position: Point,
+    pub end_position: Point,
+    pub kind: Option<&'a str>,
+    pub receiver_type: Option<&'a str>,
+    pub visibility: Option<&'a str>,
+    pub source_line: Option<&'a str>,
 }
 
 pub struct StoreFile<'a> {
     file_id: i64,
+    fold_names: bool,
     db: Transaction<'a>,
 }
 
@@ -20,116 +108,702 @@ impl Store {
     pub fn new(db_path: PathBuf) -> rusqlite::Result<Self> {
         let db = Connection::open(&db_path)?;
         db.set_prepared_statement_cache_capacity(20);
+        // Each indexing thread has its own connection, so writers regularly
+        // find the database locked by another thread. Rather than failing
+        // with SQLITE_BUSY, keep retrying until the lock is released.
         db.busy_handler(Some(|_| {
             thread::sleep(Duration::from_millis(25));
             true
         }))?;
-        Ok(Self { db, path: db_path })
+        // In WAL mode, readers don't block the indexing threads' writes, and
+        // writers only wait for each other.
+        db.execute_batch("PRAGMA journal_mode = WAL")?;
+        // Deleting a file relies on the schema's cascades to remove its
+        // definitions, references and scopes. SQLite only enforces foreign
+        // keys on connections that enable them, so every connection,
+        // including the indexing threads' clones, must do so.
+        db.execute_batch("PRAGMA foreign_keys = ON")?;
+        Ok(Self {
+            db,
+            path: db_path,
+            fast_import: false,
+            synchronous_before_fast_import: None,
+            kind_priority: Vec::new(),
+            case_insensitive_languages: Vec::new(),
+        })
     }
 
     pub fn clone(&self) -> rusqlite::Result<Self> {
-        Self::new(self.path.clone())
+        let mut result = Self::new(self.path.clone())?;
+        result.set_fast_import(self.fast_import)?;
+        result.set_kind_priority(self.kind_priority.clone());
+        result.set_case_insensitive_languages(self.case_insensitive_languages.clone());
+        Ok(result)
+    }
+
+    // Stop waiting for writes to reach the disk before committing. This makes
+    // a bulk import much faster, but if the process or machine crashes during
+    // the import, the database can be left corrupted and must be rebuilt.
+    // The setting is per-connection, so it is also applied to clones. When
+    // it is turned off, the connection's previous setting is restored.
+    pub fn set_fast_import(&mut self, enabled: bool) -> rusqlite::Result<()> {
+        if enabled && self.synchronous_before_fast_import.is_none() {
+            let synchronous = self
+                .db
+                .query_row("PRAGMA synchronous", &[], |row| row.get::<usize, i64>(0))?;
+            self.db.execute_batch("PRAGMA synchronous = OFF")?;
+            self.synchronous_before_fast_import = Some(synchronous);
+        } else if !enabled {
+            if let Some(synchronous) = self.synchronous_before_fast_import.take() {
+                self.db.execute_batch(&format!("PRAGMA synchronous = {}", synchronous))?;
+            }
+        }
+        self.fast_import = enabled;
+        Ok(())
+    }
+
+    // Copy the contents of the WAL file back into the database and truncate
+    // it. SQLite does this gradually on its own, but after a large index run
+    // the WAL file can be left much larger than it needs to be.
+    //
+    // A checkpoint that is blocked by another connection is retried a few
+    // times. Returns false if it never completed, in which case the WAL file
+    // is left as it is until a later checkpoint.
+    pub fn checkpoint(&self) -> rusqlite::Result<bool> {
+        for attempt in 0..CHECKPOINT_ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(CHECKPOINT_RETRY_INTERVAL_MS));
+            }
+            let is_busy = self.db.query_row("PRAGMA wal_checkpoint(TRUNCATE)", &[], |row| {
+                row.get::<usize, i64>(0) != 0
+            })?;
+            if !is_busy {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // When several definitions match a reference equally well, prefer the ones
+    // whose kinds come first in this list. By default, kinds are not ranked
+    // when finding the definition of a reference, and are ranked in the order
+    // of `RESOLVE_KIND_PRIORITY` when resolving a bare name.
+    pub fn set_kind_priority(&mut self, kind_priority: Vec<String>) {
+        self.kind_priority = kind_priority;
+    }
+
+    // When looking up definitions by name, definitions in these languages
+    // also match names that differ from theirs only in case.
+    pub fn set_case_insensitive_languages(&mut self, languages: Vec<String>) {
+        self.case_insensitive_languages = languages;
+    }
+
+    fn is_case_insensitive(&self, language: Option<&str>) -> bool {
+        language.map_or(false, |language| {
+            self.case_insensitive_languages.iter().any(|l| l == language)
+        })
+    }
+
+    // The same check as `is_case_insensitive`, for use within queries. Each
+    // language name is surrounded by commas, so a file's language is checked
+    // with `instr(?, ',' || files.language || ',') > 0`.
+    fn case_insensitive_language_list(&self) -> String {
+        let mut result = String::from(",");
+        for language in self.case_insensitive_languages.iter() {
+            result.push_str(language);
+            result.push(',');
+        }
+        result
+    }
+
+    // The database's `user_version` records how many of the migrations have
+    // been applied to it. Databases that were created by a newer version of
+    // this program are rejected, rather than failing later with confusing
+    // errors about missing tables or columns. So are databases that were
+    // created before versions were recorded, whose tables already exist but
+    // lack most of the columns that the migrations expect.
+    pub fn initialize(&mut self) -> io::Result<()> {
+        let version = self
+            .db
+            .query_row("PRAGMA user_version", &[], |row| row.get::<usize, i64>(0))
+            .map_err(sql_io_error)? as usize;
+        if version == 0 && self.has_table("files").map_err(sql_io_error)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The index at {} was created by an older version of tree-tags, \
+                     and can't be upgraded. Delete the index and rebuild it.",
+                    self.path.display()
+                ),
+            ));
+        }
+        if version > SCHEMA_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The index at {} has schema version {}, but this version of \
+                     tree-tags only supports version {}. Upgrade tree-tags, or \
+                     delete the index and rebuild it.",
+                    self.path.display(),
+                    version,
+                    SCHEMA_VERSION
+                ),
+            ));
+        }
+        self.migrate(version).map_err(sql_io_error)
+    }
+
+    // Migrations that rebuild tables or build indexes can take a while on a
+    // large existing database, so progress is reported in that case.
+    fn migrate(&mut self, version: usize) -> rusqlite::Result<()> {
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let report_progress = self.has_indexed_files()?;
+        if report_progress {
+            let missing_index_names = self.missing_index_names()?;
+            eprintln!(
+                "Migrating the database to schema version {}{}. This is a one-time operation.",
+                SCHEMA_VERSION,
+                if missing_index_names.is_empty() {
+                    String::new()
+                } else {
+                    format!(" and building indexes ({})", missing_index_names.join(", "))
+                }
+            );
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.db.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+            tx.commit()?;
+        }
+
+        if report_progress {
+            eprintln!("Migrated the database in {:?}", start.elapsed());
+        }
+        Ok(())
+    }
+
+    // Store each name that is referenced in a file once, rather than once per
+    // reference, which makes the index smaller when names are repeated. The
+    // index keeps this layout when it is updated. Returns false if it already
+    // used it.
+    pub fn compact_references(&mut self) -> rusqlite::Result<bool> {
+        if has_compact_references(&self.db)? {
+            return Ok(false);
+        }
+        let tx = self.db.transaction()?;
+        tx.execute_batch(include_str!("./compact_references.sql"))?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn has_compact_references(&self) -> rusqlite::Result<bool> {
+        has_compact_references(&self.db)
+    }
+
+    fn has_table(&self, name: &str) -> rusqlite::Result<bool> {
+        self.db.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            &[&name],
+            |row| row.get::<usize, i64>(0) > 0,
+        )
+    }
+
+    fn has_indexed_files(&self) -> rusqlite::Result<bool> {
+        if !self.has_table("files")? {
+            return Ok(false);
+        }
+        let file_count = self
+            .db
+            .query_row("SELECT count(*) FROM files", &[], |row| row.get::<usize, i64>(0))?;
+        Ok(file_count > 0)
     }
 
-    pub fn initialize(&mut self) -> rusqlite::Result<()> {
-        self.db.execute_batch(include_str!("./schema.sql"))
+    // Returns the names of the indexes created by the migrations that don't
+    // exist yet.
+    fn missing_index_names(&self) -> rusqlite::Result<Vec<&'static str>> {
+        let mut statement = self
+            .db
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index'")?;
+        let mut existing_index_names = HashSet::new();
+        for row in statement.query_map(&[], |row| row.get::<usize, String>(0))? {
+            existing_index_names.insert(row?);
+        }
+
+        Ok(MIGRATIONS
+            .iter()
+            .flat_map(|migration| migration.lines())
+            .filter_map(|line| line.trim().splitn(2, "CREATE INDEX IF NOT EXISTS ").nth(1))
+            .filter_map(|rest| rest.split_whitespace().next())
+            .filter(|name| !existing_index_names.contains(*name))
+            .collect())
     }
 
+    // Deletes the given file, or all of the files within the given directory.
+    // Files in sibling directories whose names merely start with the same
+    // characters are left alone.
     pub fn delete_files(&mut self, path: &Path) -> rusqlite::Result<()> {
+        let path = path.as_os_str().as_bytes().to_vec();
+        let mut prefix = path.clone();
+        if !prefix.ends_with(b"/") {
+            prefix.push(b'/');
+        }
         self.db.execute(
-            "DELETE FROM files WHERE instr(path, ?1) = 1",
-            &[&path.as_os_str().as_bytes()]
+            "DELETE FROM files WHERE path = ?1 OR instr(path, ?2) = 1",
+            &[&path, &prefix],
         )?;
         Ok(())
     }
 
-    pub fn file(&mut self, path: &Path) -> rusqlite::Result<StoreFile> {
+    // The file's symbols are removed along with it, through the foreign keys'
+    // cascading deletes. Returns whether the file was indexed.
+    pub fn delete_file(&mut self, path: &Path) -> rusqlite::Result<bool> {
+        let deleted_count = self.db.execute(
+            "DELETE FROM files WHERE path = ?1",
+            &[&path.as_os_str().as_bytes()],
+        )?;
+        Ok(deleted_count > 0)
+    }
+
+    // Returns the paths of all indexed files within the given directory.
+    pub fn file_paths(&mut self, dir_path: &Path) -> Result<Vec<PathBuf>> {
+        let dir_path = dir_path.as_os_str().as_bytes().to_vec();
+        let mut dir_prefix = dir_path.clone();
+        dir_prefix.push(b'/');
+        let mut statement = self.db.prepare_cached(
+            "SELECT path FROM files WHERE path = ?1 OR instr(path, ?2) = 1",
+        )?;
+        let rows = statement.query_map(&[&dir_path, &dir_prefix], |row| {
+            PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0)))
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    // Removes any rows that refer to files or scopes which no longer exist,
+    // returning the number of rows that were deleted. File paths are unique,
+    // so each path already maps to a single file.
+    pub fn repair(&mut self) -> Result<usize> {
         let tx = self.db.transaction()?;
+        let mut orphan_count = 0;
+        let mut tables = vec!["scopes", "local_defs", "local_refs", "defs"];
+        if has_compact_references(&tx)? {
+            tables.extend(&["ref_positions", "ref_names"]);
+        } else {
+            tables.push("refs");
+        }
+        tables.extend(&["implementations", "docs"]);
+        for table in tables.iter() {
+            orphan_count += tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE file_id NOT IN (SELECT id FROM files)",
+                    table
+                ),
+                &[],
+            )?;
+        }
+        orphan_count += tx.execute(
+            "
+                DELETE FROM scopes
+                WHERE parent_id IS NOT NULL AND parent_id NOT IN (SELECT id FROM scopes)
+            ",
+            &[],
+        )?;
+        for table in ["local_defs", "local_refs"].iter() {
+            orphan_count += tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE scope_id NOT IN (SELECT id FROM scopes)",
+                    table
+                ),
+                &[],
+            )?;
+        }
+        orphan_count += tx.execute(
+            "DELETE FROM local_refs WHERE definition_id NOT IN (SELECT id FROM local_defs)",
+            &[],
+        )?;
+
+        tx.commit()?;
+        Ok(orphan_count)
+    }
+
+    // For case-insensitive languages, names are also stored in lowercase, so
+    // that they can be matched against references that differ in case.
+    pub fn file(
+        &mut self,
+        path: &Path,
+        language: &str,
+        fold_names: bool,
+    ) -> rusqlite::Result<StoreFile> {
+        // Readers on other connections keep seeing the file's previous rows
+        // until the new ones are committed. Taking the write lock up front
+        // keeps concurrent crawlers from deadlocking when they both try to
+        // upgrade a read lock.
+        let tx = self.db.transaction_with_behavior(TransactionBehavior::Immediate)?;
         {
             let mut stmt = tx.prepare_cached("DELETE FROM files WHERE path = ?1")?;
             stmt.execute(&[&path.as_os_str().as_bytes()])?;
-            let mut stmt = tx.prepare_cached("INSERT INTO files (path) VALUES (?1)")?;
-            stmt.execute(&[&path.as_os_str().as_bytes()])?;
+            let mut stmt = tx.prepare_cached("INSERT INTO files (path, language) VALUES (?1, ?2)")?;
+            stmt.execute(&[&path.as_os_str().as_bytes(), &language])?;
         }
         let file_id = tx.last_insert_rowid();
-        Ok(StoreFile { file_id, db: tx })
+        Ok(StoreFile {
+            file_id,
+            fold_names,
+            db: tx,
+        })
     }
 
-    pub fn find_definition(
-        &mut self,
-        path: &Path,
-        position: Point,
-    ) -> Result<Vec<(PathBuf, Point, usize)>> {
-        let file_id: i64 = self.db.query_row(
+    pub fn file_id(&mut self, path: &Path) -> Result<i64> {
+        self.db.query_row(
             "SELECT id FROM files WHERE path = ?1",
             &[&path.as_os_str().as_bytes()],
             |row| row.get(0),
+        )
+    }
+
+    pub fn file_modified_time(&mut self, path: &Path) -> Result<Option<i64>> {
+        let result = self.db.query_row(
+            "SELECT mtime FROM files WHERE path = ?1",
+            &[&path.as_os_str().as_bytes()],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(mtime) => Ok(mtime),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn file_content_hash(&mut self, path: &Path) -> Result<Option<i64>> {
+        let result = self.db.query_row(
+            "SELECT content_hash FROM files WHERE path = ?1",
+            &[&path.as_os_str().as_bytes()],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(content_hash) => Ok(content_hash),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_file_modified_time(&mut self, path: &Path, mtime: i64) -> Result<()> {
+        self.db.execute(
+            "UPDATE files SET mtime = ?1 WHERE path = ?2",
+            &[&mtime, &path.as_os_str().as_bytes()],
+        )?;
+        Ok(())
+    }
+
+    // Returns the path, content hash and syntax error count of each indexed
+    // file within the given directory, or of every indexed file.
+    pub fn file_summaries(
+        &mut self,
+        dir_path: Option<&Path>,
+    ) -> Result<Vec<(PathBuf, Option<i64>, Option<i64>)>> {
+        let dir_path = dir_path.map(|path| path.as_os_str().as_bytes().to_vec());
+        let dir_prefix = dir_path.as_ref().map(|path| {
+            let mut prefix = path.clone();
+            prefix.push(b'/');
+            prefix
+        });
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    path,
+                    content_hash,
+                    error_count
+                FROM
+                    files
+                WHERE
+                    ?1 IS NULL OR path = ?1 OR instr(path, ?2) = 1
+                ORDER BY
+                    path
+            ",
         )?;
+        let rows = statement.query_map(&[&dir_path, &dir_prefix], |row| {
+            (
+                PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0))),
+                row.get::<usize, Option<i64>>(1),
+                row.get::<usize, Option<i64>>(2),
+            )
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+        Ok(result)
+    }
+
+    pub fn symbol_counts(&mut self, path: &Path) -> Result<(usize, usize)> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            store.db.query_row(
+                "
+                    SELECT
+                        (SELECT count(*) FROM defs WHERE file_id = ?1),
+                        (SELECT count(*) FROM refs WHERE file_id = ?1)
+                ",
+                &[&file_id],
+                |row| (row.get::<usize, i64>(0) as usize, row.get::<usize, i64>(1) as usize),
+            )
+        })
+    }
+
+    // Queries that look up a file's id and then its rows run within a single
+    // read transaction. Otherwise, if the file were reindexed in between, the
+    // id would refer to rows that had already been deleted.
+    fn read<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        self.db.execute_batch("BEGIN DEFERRED")?;
+        let result = f(self);
+        match result {
+            Ok(_) => self.db.execute_batch("COMMIT")?,
+            Err(_) => self.db.execute_batch("ROLLBACK")?,
+        }
+        result
+    }
+
+    fn file_path(&self, file_id: i64) -> Result<PathBuf> {
+        self.db.query_row("SELECT path FROM files WHERE id = ?1", &[&file_id], |row| {
+            PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0)))
+        })
+    }
+
+    fn file_language(&self, file_id: i64) -> Result<Option<String>> {
+        self.db.query_row("SELECT language FROM files WHERE id = ?1", &[&file_id], |row| {
+            row.get::<usize, Option<String>>(0)
+        })
+    }
+
+    // Returns at most `limit` definitions, along with whether there were
+    // more definitions that didn't fit.
+    pub fn find_definition(
+        &mut self,
+        path: &Path,
+        position: Point,
+        limit: usize,
+    ) -> Result<(Vec<Definition>, bool)> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            store.find_definition_in_file(file_id, position, limit)
+        })
+    }
 
+    pub fn find_definition_in_file(
+        &mut self,
+        file_id: i64,
+        position: Point,
+        limit: usize,
+    ) -> Result<(Vec<Definition>, bool)> {
+        // If local references overlap at this position, use the shortest.
         let local_result = self.db.query_row(
             "
                 SELECT
+                    files.path,
                     local_defs.row,
                     local_defs.column,
                     local_defs.length
                 FROM
+                    files,
                     local_refs,
                     local_defs
                 WHERE
+                    files.id = local_refs.file_id AND
                     local_refs.definition_id = local_defs.id AND
                     local_refs.file_id = ?1 AND
                     local_refs.row = ?2 AND
                     local_refs.column <= ?3 AND
                     local_refs.column + local_refs.length > ?3
+                ORDER BY
+                    local_refs.length
+                LIMIT
+                    1
             ",
             &[&file_id, &(position.row as i64), &(position.column as i64)],
             |row| {
                 (
+                    OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
                     Point {
-                        row: row.get(0),
-                        column: row.get(1),
+                        row: row.get(1),
+                        column: row.get(2),
                     },
-                    row.get::<usize, i64>(2),
+                    row.get::<usize, i64>(3),
                 )
             },
         );
 
         match local_result {
             Err(rusqlite::Error::QueryReturnedNoRows) => {}
-            Ok((position, length)) => return Ok(vec![(path.to_owned(), position, length as usize)]),
+            Ok((path, position, length)) => {
+                let definition = Definition {
+                    path,
+                    position,
+                    length: length as usize,
+                    kind: None,
+                    module_path: None,
+                    proximity: 0,
+                };
+                return Ok((vec![definition], false));
+            }
             Err(e) => return Err(e.into()),
         }
 
+        let mut statement = self.db.prepare_cached(FIND_DEFINITION_QUERY)?;
+
+        // The candidates are ranked below, but only a bounded number of them
+        // are read, preferring the ones that match the receiver's type and
+        // the ones in the same file, so that common names stay fast.
+        let receiver_type = self.receiver_type(file_id, position)?;
+        let candidate_limit = (limit * FIND_DEFINITION_CANDIDATE_FACTOR + 1) as i64;
+        let rows = statement.query_map(
+            &[
+                &file_id,
+                &(position.row as i64),
+                &(position.column as i64),
+                &receiver_type,
+                &candidate_limit,
+                &self.case_insensitive_language_list(),
+            ],
+            |row| {
+                (
+                    PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0))),
+                    Point::new(row.get(1), row.get(2)),
+                    row.get::<usize, i64>(3) as usize,
+                    row.get::<usize, bool>(4),
+                    row.get::<usize, Option<String>>(5),
+                    row.get::<usize, String>(6),
+                )
+            },
+        )?;
+
+        let reference_module_path = self.enclosing_module_path(file_id, position)?;
+        let mut candidates = Vec::new();
+        for row in rows {
+            let (path, position, length, matches_receiver, kind, module_path) = row?;
+            let proximity = module_proximity(&reference_module_path, &module_path);
+            candidates.push((
+                path,
+                position,
+                length,
+                matches_receiver,
+                kind,
+                module_path,
+                proximity,
+            ));
+        }
+
+        // Prefer definitions on the receiver's type, then ones of the
+        // configured kinds, then ones in the same file, then ones that share
+        // more of the reference's module path, then ones that are closer to
+        // the reference in the directory tree.
+        let path = self.file_path(file_id)?;
+        let kind_priority = &self.kind_priority;
+        candidates.sort_by_key(
+            |(candidate_path, position, _, matches_receiver, kind, _, proximity)| {
+                (
+                    !matches_receiver,
+                    kind_rank(kind.as_ref().map(String::as_str), kind_priority),
+                    *candidate_path != path,
+                    Reverse(*proximity),
+                    path_distance(&path, candidate_path),
+                    candidate_path.clone(),
+                    *position,
+                )
+            },
+        );
+
+        let has_more = candidates.len() > limit;
+        Ok((
+            candidates
+                .into_iter()
+                .take(limit)
+                .map(|(path, position, length, _, kind, module_path, proximity)| Definition {
+                    path,
+                    position,
+                    length,
+                    kind,
+                    module_path: Some(join_module_path(&module_path)),
+                    proximity,
+                })
+                .collect(),
+            has_more,
+        ))
+    }
+
+    // Guess the module path at a position from the innermost definition that
+    // contains it. Positions outside of any definition are at the top level.
+    fn enclosing_module_path(&self, file_id: i64, position: Point) -> Result<String> {
+        Ok(self
+            .enclosing_definitions_in_file(file_id, position)?
+            .pop()
+            .map_or(String::new(), |(_, _, _, _, module_path)| module_path))
+    }
+
+    // Returns the definitions whose extents contain the given position, from
+    // the outermost to the innermost.
+    pub fn enclosing_definitions(
+        &mut self,
+        path: &Path,
+        position: Point,
+    ) -> Result<Vec<(String, Option<String>, Point, usize)>> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            Ok(store
+                .enclosing_definitions_in_file(file_id, position)?
+                .into_iter()
+                .map(|(name, kind, position, length, _)| (name, kind, position, length))
+                .collect())
+        })
+    }
+
+    fn enclosing_definitions_in_file(
+        &self,
+        file_id: i64,
+        position: Point,
+    ) -> Result<Vec<(String, Option<String>, Point, usize, String)>> {
         let mut statement = self.db.prepare_cached(
             "
                 SELECT
-                    files.path,
-                    defs.name_start_row,
-                    defs.name_start_column,
-                    length(defs.name)
+                    name,
+                    kind,
+                    name_start_row,
+                    name_start_column,
+                    length(CAST(name AS BLOB)),
+                    module_path
                 FROM
-                    files,
-                    defs,
-                    refs
+                    defs
                 WHERE
-                    files.id == defs.file_id AND
-                    defs.name = refs.name AND
-                    refs.file_id = ?1 AND
-                    refs.row = ?2 AND
-                    refs.column <= ?3 AND
-                    refs.column + length(refs.name) > ?3
-                LIMIT
-                    50
+                    file_id = ?1 AND
+                    (start_row < ?2 OR (start_row = ?2 AND start_column <= ?3)) AND
+                    (end_row > ?2 OR (end_row = ?2 AND end_column >= ?3))
+                ORDER BY
+                    start_row,
+                    start_column,
+                    end_row DESC,
+                    end_column DESC
             ",
         )?;
-
         let rows = statement.query_map(
             &[&file_id, &(position.row as i64), &(position.column as i64)],
             |row| {
                 (
-                    OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
-                    Point::new(row.get(1), row.get(2)),
-                    row.get::<usize, i64>(3) as usize,
+                    row.get::<usize, String>(0),
+                    row.get::<usize, Option<String>>(1),
+                    Point::new(row.get(2), row.get(3)),
+                    row.get::<usize, i64>(4) as usize,
+                    row.get::<usize, String>(5),
                 )
             },
         )?;
@@ -138,85 +812,1622 @@ impl Store {
         for row in rows {
             result.push(row?);
         }
-
         Ok(result)
     }
-}
 
-impl<'a> StoreFile<'a> {
-    pub fn insert_local_ref(
-        &mut self,
-        local_def_id: i64,
-        name: &'a str,
-        position: Point,
-    ) -> Result<()> {
-        let mut stmt = self.db.prepare_cached(
+    // Guess the type of the receiver of a method call, as in `a.b()`, from
+    // the declared type of the local variable that precedes the method name.
+    fn receiver_type(&self, file_id: i64, position: Point) -> Result<Option<String>> {
+        let result = self.db.query_row(
             "
-                INSERT INTO local_refs
-                (file_id, definition_id, row, column, length)
-                VALUES
-                (?1, ?2, ?3, ?4, ?5)
+                SELECT
+                    local_defs.declared_type
+                FROM
+                    refs,
+                    local_refs,
+                    local_defs
+                WHERE
+                    refs.file_id = ?1 AND
+                    refs.row = ?2 AND
+                    refs.column <= ?3 AND
+                    refs.column + refs.length > ?3 AND
+                    local_refs.file_id = refs.file_id AND
+                    local_refs.row = refs.row AND
+                    local_refs.column + local_refs.length < refs.column AND
+                    local_refs.column + local_refs.length >= refs.column - 2 AND
+                    local_refs.definition_id = local_defs.id AND
+                    local_defs.declared_type IS NOT NULL
             ",
-        )?;
-        stmt.execute(&[
-            &self.file_id,
-            &local_def_id,
-            &position.row,
-            &position.column,
-            &(name.as_bytes().len() as i64),
-        ])?;
-        Ok(())
-    }
+            &[&file_id, &(position.row as i64), &(position.column as i64)],
+            |row| row.get(0),
+        );
 
-    pub fn insert_local_def(&mut self, name: &'a str, position: Point) -> Result<i64> {
-        let mut stmt = self.db.prepare_cached(
-            "
-                INSERT INTO local_defs
-                (file_id, row, column, length)
-                VALUES
-                (?1, ?2, ?3, ?4)
-            ",
-        )?;
-        stmt.execute(&[
-            &self.file_id,
-            &position.row,
-            &position.column,
-            &(name.as_bytes().len() as i64),
-        ])?;
-        Ok(self.db.last_insert_rowid())
+        match result {
+            Ok(declared_type) => Ok(Some(declared_type)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn insert_ref(
+    pub fn find_usages(
         &mut self,
-        name: &'a str,
+        path: &Path,
         position: Point,
-        kind: Option<&'a str>,
-    ) -> Result<()> {
-        let mut stmt = self.db.prepare_cached(
-            "
-                INSERT INTO refs
-                (file_id, name, row, column, kind)
-                VALUES
-                (?1, ?2, ?3, ?4, ?5)
-            ",
-        )?;
-        stmt.execute(&[&self.file_id, &name, &position.row, &position.column, &kind])?;
-        Ok(())
+        within: Option<&Path>,
+    ) -> Result<Vec<Location>> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            store.find_usages_in_file(file_id, position, within)
+        })
     }
 
-    pub fn insert_def(
+    fn find_usages_in_file(
         &mut self,
-        name: &'a str,
-        name_position: Point,
-        start_position: Point,
-        end_position: Point,
-        kind: Option<&'a str>,
-        module_path: &Vec<&'a str>,
-    ) -> Result<()> {
-        let mut module_path_string = String::with_capacity(
-            module_path
-                .iter()
-                .map(|entry| entry.as_bytes().len() + 1)
+        file_id: i64,
+        position: Point,
+        within: Option<&Path>,
+    ) -> Result<Vec<Location>> {
+        let within_path = within.map(|path| path.as_os_str().as_bytes().to_vec());
+        let within_dir_path = within_path.as_ref().map(|path| {
+            let mut dir_path = path.clone();
+            dir_path.push(b'/');
+            dir_path
+        });
+
+        // The position can be either on a usage or on the definition itself.
+        let local_definition_id = self.db.query_row(
+            "
+                SELECT
+                    definition_id,
+                    length
+                FROM
+                    local_refs
+                WHERE
+                    file_id = ?1 AND
+                    row = ?2 AND
+                    column <= ?3 AND
+                    column + length > ?3
+                UNION ALL
+                SELECT
+                    id,
+                    length
+                FROM
+                    local_defs
+                WHERE
+                    file_id = ?1 AND
+                    row = ?2 AND
+                    column <= ?3 AND
+                    column + length > ?3
+                ORDER BY
+                    length
+                LIMIT
+                    1
+            ",
+            &[&file_id, &(position.row as i64), &(position.column as i64)],
+            |row| row.get::<usize, i64>(0),
+        );
+
+        let mut result = Vec::new();
+        match local_definition_id {
+            Ok(local_definition_id) => {
+                let mut statement = self.db.prepare_cached(
+                    "
+                        SELECT DISTINCT
+                            files.path,
+                            local_refs.row,
+                            local_refs.column,
+                            local_refs.length
+                        FROM
+                            files,
+                            local_refs
+                        WHERE
+                            files.id = local_refs.file_id AND
+                            local_refs.definition_id = ?1 AND
+                            (?2 IS NULL OR files.path = ?2 OR instr(files.path, ?3) = 1)
+                        ORDER BY
+                            local_refs.row,
+                            local_refs.column
+                    ",
+                )?;
+                let rows = statement.query_map(
+                    &[&local_definition_id, &within_path, &within_dir_path],
+                    |row| Location {
+                        path: OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
+                        position: Point::new(row.get(1), row.get(2)),
+                        length: row.get::<usize, i64>(3) as usize,
+                    },
+                )?;
+                for row in rows {
+                    result.push(row?);
+                }
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let name = self.db.query_row(
+                    "
+                        SELECT
+                            name
+                        FROM
+                            refs
+                        WHERE
+                            file_id = ?1 AND
+                            row = ?2 AND
+                            column <= ?3 AND
+                            column + length > ?3
+                        UNION ALL
+                        SELECT
+                            name
+                        FROM
+                            defs
+                        WHERE
+                            file_id = ?1 AND
+                            name_start_row = ?2 AND
+                            name_start_column <= ?3 AND
+                            name_start_column + length(CAST(name AS BLOB)) > ?3
+                        LIMIT
+                            1
+                    ",
+                    &[&file_id, &(position.row as i64), &(position.column as i64)],
+                    |row| row.get::<usize, String>(0),
+                );
+                let name = match name {
+                    Ok(name) => name,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(result),
+                    Err(e) => return Err(e),
+                };
+
+                // Other names that only differ in case are only used when both
+                // the symbol's file and the usage's file are case-insensitive.
+                let language = self.file_language(file_id)?;
+                let fold_case = self.is_case_insensitive(language.as_ref().map(String::as_str));
+                let mut statement = self.db.prepare_cached(FIND_USAGES_QUERY)?;
+                let rows = statement.query_map(
+                    &[
+                        &name,
+                        &name.to_lowercase(),
+                        &fold_case,
+                        &self.case_insensitive_language_list(),
+                        &within_path,
+                        &within_dir_path,
+                    ],
+                    |row| Location {
+                        path: OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
+                        position: Point::new(row.get(1), row.get(2)),
+                        length: row.get::<usize, i64>(3) as usize,
+                    },
+                )?;
+                for row in rows {
+                    result.push(row?);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(result)
+    }
+
+    // Find the definitions that implement the symbol at the given position.
+    // For an interface, these are the definitions that declare themselves as
+    // implementing it. For a member of an interface, these are the members
+    // with the same name that are defined within implementations.
+    pub fn find_implementations(
+        &mut self,
+        path: &Path,
+        position: Point,
+    ) -> Result<Vec<(PathBuf, Point, usize, String)>> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            store.find_implementations_in_file(file_id, position)
+        })
+    }
+
+    fn find_implementations_in_file(
+        &mut self,
+        file_id: i64,
+        position: Point,
+    ) -> Result<Vec<(PathBuf, Point, usize, String)>> {
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT DISTINCT
+                    files.path,
+                    implementations.row,
+                    implementations.column,
+                    length(CAST(implementations.name AS BLOB)),
+                    implementations.interface
+                FROM
+                    files,
+                    implementations,
+                    (
+                        SELECT
+                            name
+                        FROM
+                            refs
+                        WHERE
+                            file_id = ?1 AND
+                            row = ?2 AND
+                            column <= ?3 AND
+                            column + length > ?3
+                        UNION
+                        SELECT
+                            name
+                        FROM
+                            defs
+                        WHERE
+                            file_id = ?1 AND
+                            name_start_row = ?2 AND
+                            name_start_column <= ?3 AND
+                            name_start_column + length(CAST(name AS BLOB)) > ?3
+                    ) AS symbol
+                WHERE
+                    files.id = implementations.file_id AND
+                    (
+                        implementations.interface = symbol.name OR
+                        implementations.name = symbol.name
+                    ) AND
+                    NOT (
+                        implementations.file_id = ?1 AND
+                        implementations.row = ?2 AND
+                        implementations.column <= ?3 AND
+                        implementations.column + length(CAST(implementations.name AS BLOB)) > ?3
+                    )
+                ORDER BY
+                    files.path,
+                    implementations.row,
+                    implementations.column
+            ",
+        )?;
+
+        let rows = statement.query_map(
+            &[&file_id, &(position.row as i64), &(position.column as i64)],
+            |row| {
+                (
+                    OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
+                    Point::new(row.get(1), row.get(2)),
+                    row.get::<usize, i64>(3) as usize,
+                    row.get::<usize, String>(4),
+                )
+            },
+        )?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn symbols_in_scope(
+        &mut self,
+        path: &Path,
+        position: Point,
+    ) -> Result<Vec<(String, Point, Option<String>)>> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            store.symbols_in_scope_in_file(file_id, position)
+        })
+    }
+
+    pub fn symbols_in_scope_in_file(
+        &mut self,
+        file_id: i64,
+        position: Point,
+    ) -> Result<Vec<(String, Point, Option<String>)>> {
+        let mut result = Vec::new();
+        let mut names = HashSet::new();
+
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    local_defs.name,
+                    local_defs.row,
+                    local_defs.column
+                FROM
+                    scopes,
+                    local_defs
+                WHERE
+                    scopes.id = local_defs.scope_id AND
+                    scopes.file_id = ?1 AND
+                    (scopes.start_row < ?2 OR (scopes.start_row = ?2 AND scopes.start_column <= ?3)) AND
+                    (scopes.end_row > ?2 OR (scopes.end_row = ?2 AND scopes.end_column > ?3)) AND
+                    (
+                        local_defs.is_hoisted OR
+                        local_defs.row < ?2 OR
+                        (local_defs.row = ?2 AND local_defs.column <= ?3)
+                    )
+                ORDER BY
+                    scopes.start_row DESC,
+                    scopes.start_column DESC,
+                    local_defs.row DESC,
+                    local_defs.column DESC
+            ",
+        )?;
+        let rows = statement.query_map(
+            &[&file_id, &(position.row as i64), &(position.column as i64)],
+            |row| (row.get::<usize, String>(0), Point::new(row.get(1), row.get(2))),
+        )?;
+        for row in rows {
+            let (name, position) = row?;
+            if names.insert(name.clone()) {
+                result.push((name, position, None));
+            }
+        }
+
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    name,
+                    name_start_row,
+                    name_start_column,
+                    kind
+                FROM
+                    defs
+                WHERE
+                    file_id = ?1
+                ORDER BY
+                    name_start_row,
+                    name_start_column
+            ",
+        )?;
+        let rows = statement.query_map(&[&file_id], |row| {
+            (
+                row.get::<usize, String>(0),
+                Point::new(row.get(1), row.get(2)),
+                row.get::<usize, Option<String>>(3),
+            )
+        })?;
+        for row in rows {
+            let (name, position, kind) = row?;
+            if names.insert(name.clone()) {
+                result.push((name, position, kind));
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn locals(
+        &mut self,
+        path: &Path,
+    ) -> Result<Vec<(String, Point, usize, Vec<(Point, usize)>)>> {
+        self.read(|store| {
+            let file_id = store.file_id(path)?;
+            store.locals_in_file(file_id)
+        })
+    }
+
+    fn locals_in_file(
+        &mut self,
+        file_id: i64,
+    ) -> Result<Vec<(String, Point, usize, Vec<(Point, usize)>)>> {
+        let mut result = Vec::new();
+        let mut indices_by_id = HashMap::new();
+
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    id,
+                    name,
+                    row,
+                    column,
+                    length
+                FROM
+                    local_defs
+                WHERE
+                    file_id = ?1
+                ORDER BY
+                    row,
+                    column
+            ",
+        )?;
+        let rows = statement.query_map(&[&file_id], |row| {
+            (
+                row.get::<usize, i64>(0),
+                row.get::<usize, String>(1),
+                Point::new(row.get(2), row.get(3)),
+                row.get::<usize, i64>(4) as usize,
+            )
+        })?;
+        for row in rows {
+            let (id, name, position, length) = row?;
+            indices_by_id.insert(id, result.len());
+            result.push((name, position, length, Vec::new()));
+        }
+
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    definition_id,
+                    row,
+                    column,
+                    length
+                FROM
+                    local_refs
+                WHERE
+                    file_id = ?1
+                ORDER BY
+                    row,
+                    column
+            ",
+        )?;
+        let rows = statement.query_map(&[&file_id], |row| {
+            (
+                row.get::<usize, i64>(0),
+                Point::new(row.get(1), row.get(2)),
+                row.get::<usize, i64>(3) as usize,
+            )
+        })?;
+        for row in rows {
+            let (definition_id, position, length) = row?;
+            if let Some(index) = indices_by_id.get(&definition_id) {
+                result[*index].3.push((position, length));
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Returns the references that match neither a definition anywhere in the
+    // index nor a local definition, optionally only within one directory.
+    // As when finding definitions, names that differ in case only match when
+    // both files are in case-insensitive languages.
+    pub fn unresolved_references(
+        &mut self,
+        dir_path: Option<&Path>,
+    ) -> Result<Vec<(PathBuf, Point, String, Option<String>)>> {
+        let dir_path = dir_path.map(|path| path.as_os_str().as_bytes().to_vec());
+        let dir_prefix = dir_path.as_ref().map(|path| {
+            let mut prefix = path.clone();
+            prefix.push(b'/');
+            prefix
+        });
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    files.path,
+                    refs.row,
+                    refs.column,
+                    refs.name,
+                    files.language
+                FROM
+                    files,
+                    refs
+                WHERE
+                    files.id = refs.file_id AND
+                    (?1 IS NULL OR files.path = ?1 OR instr(files.path, ?2) = 1) AND
+                    NOT EXISTS (
+                        SELECT
+                            1
+                        FROM
+                            defs,
+                            files AS def_files
+                        WHERE
+                            def_files.id = defs.file_id AND
+                            (
+                                defs.name = refs.name OR (
+                                    defs.name_fold = refs.name_fold AND
+                                    instr(?3, ',' || def_files.language || ',') > 0 AND
+                                    instr(?3, ',' || files.language || ',') > 0
+                                )
+                            )
+                    ) AND
+                    NOT EXISTS (
+                        SELECT 1 FROM local_refs
+                        WHERE
+                            local_refs.file_id = refs.file_id AND
+                            local_refs.row = refs.row AND
+                            local_refs.column = refs.column
+                    )
+                ORDER BY
+                    files.path,
+                    refs.row,
+                    refs.column
+            ",
+        )?;
+        let case_insensitive_languages = self.case_insensitive_language_list();
+        let rows = statement.query_map(
+            &[&dir_path, &dir_prefix, &case_insensitive_languages],
+            |row| {
+                (
+                    PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0))),
+                    Point::new(row.get(1), row.get(2)),
+                    row.get::<usize, String>(3),
+                    row.get::<usize, Option<String>>(4),
+                )
+            },
+        )?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    // Names are only matched case-insensitively if `fold_case` is set, which
+    // is the case when the name comes from a file in a case-insensitive
+    // language, and then only against definitions in such languages. Returns
+    // at most `limit` definitions, along with whether there were more.
+    pub fn definitions_named(
+        &mut self,
+        name: &str,
+        fold_case: bool,
+        limit: usize,
+    ) -> Result<(Vec<Definition>, bool)> {
+        let mut statement = self.db.prepare_cached(DEFINITIONS_NAMED_QUERY)?;
+
+        let rows = statement.query_map(&[&name, &name.to_lowercase()], |row| {
+            (
+                OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
+                Point::new(row.get(1), row.get(2)),
+                row.get::<usize, i64>(3) as usize,
+                row.get::<usize, Option<String>>(4),
+                row.get::<usize, bool>(5),
+                row.get::<usize, Option<String>>(6),
+            )
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            let (path, position, length, kind, is_exact_match, language) = row?;
+            if is_exact_match
+                || (fold_case && self.is_case_insensitive(language.as_ref().map(String::as_str)))
+            {
+                if result.len() == limit {
+                    return Ok((result, true));
+                }
+                result.push(Definition {
+                    path,
+                    position,
+                    length,
+                    kind,
+                    module_path: None,
+                    proximity: 0,
+                });
+            }
+        }
+
+        Ok((result, false))
+    }
+
+    pub fn resolve_name(
+        &mut self,
+        name: &str,
+        limit: usize,
+    ) -> Result<Vec<Definition>> {
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT DISTINCT
+                    files.path,
+                    defs.name_start_row,
+                    defs.name_start_column,
+                    length(CAST(defs.name AS BLOB)),
+                    defs.kind,
+                    defs.module_path,
+                    coalesce(defs.visibility, 'public') = 'public',
+                    defs.name = ?1,
+                    files.language
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    (defs.name = ?1 OR defs.name_fold = ?2)
+            ",
+        )?;
+
+        let rows = statement.query_map(&[&name, &name.to_lowercase()], |row| {
+            (
+                PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0))),
+                Point::new(row.get(1), row.get(2)),
+                row.get::<usize, i64>(3) as usize,
+                row.get::<usize, Option<String>>(4),
+                row.get::<usize, String>(5),
+                row.get::<usize, bool>(6),
+                row.get::<usize, bool>(7),
+                row.get::<usize, Option<String>>(8),
+            )
+        })?;
+
+        // Definitions in case-sensitive languages only match the exact name.
+        let mut candidates = Vec::new();
+        for row in rows {
+            let (path, position, length, kind, module_path, is_public, is_exact_match, language) =
+                row?;
+            if is_exact_match || self.is_case_insensitive(language.as_ref().map(String::as_str)) {
+                candidates.push((path, position, length, kind, module_path, is_public));
+            }
+        }
+
+        // Prefer definitions of more significant kinds, then public ones,
+        // then ones that are nested in fewer modules, then ones with shorter
+        // paths. Definitions whose visibility isn't recorded count as public.
+        let kind_priority = if self.kind_priority.is_empty() {
+            RESOLVE_KIND_PRIORITY.iter().map(|kind| kind.to_string()).collect()
+        } else {
+            self.kind_priority.clone()
+        };
+        candidates.sort_by(|a, b| {
+            let a_rank = kind_rank(a.3.as_ref().map(String::as_str), &kind_priority);
+            let b_rank = kind_rank(b.3.as_ref().map(String::as_str), &kind_priority);
+            let a_key = (a_rank, !a.5, a.4.matches('\t').count(), a.0.as_os_str().len());
+            let b_key = (b_rank, !b.5, b.4.matches('\t').count(), b.0.as_os_str().len());
+            a_key.cmp(&b_key).then_with(|| (&a.0, a.1).cmp(&(&b.0, b.1)))
+        });
+
+        Ok(candidates
+            .into_iter()
+            .take(limit)
+            .map(|(path, position, length, kind, module_path, _)| Definition {
+                path,
+                position,
+                length,
+                kind,
+                module_path: Some(join_module_path(&module_path)),
+                proximity: 0,
+            })
+            .collect())
+    }
+
+    // Finds definitions whose names are equal to the given name or, if
+    // `prefix` is set, start with it.
+    pub fn find_symbol(
+        &mut self,
+        name: &str,
+        prefix: bool,
+        limit: usize,
+    ) -> Result<Vec<Definition>> {
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    files.path,
+                    defs.name_start_row,
+                    defs.name_start_column,
+                    length(CAST(defs.name AS BLOB)),
+                    defs.kind,
+                    defs.module_path
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    (
+                        defs.name = ?1 OR
+                        (?2 AND substr(defs.name, 1, length(?1)) = ?1)
+                    )
+                ORDER BY
+                    length(CAST(defs.name AS BLOB)),
+                    defs.name,
+                    files.path,
+                    defs.name_start_row,
+                    defs.name_start_column
+                LIMIT
+                    ?3
+            ",
+        )?;
+
+        let rows = statement.query_map(&[&name, &prefix, &(limit as i64)], |row| Definition {
+            path: PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0))),
+            position: Point::new(row.get(1), row.get(2)),
+            length: row.get::<usize, i64>(3) as usize,
+            kind: row.get::<usize, Option<String>>(4),
+            module_path: Some(join_module_path(&row.get::<usize, String>(5))),
+            proximity: 0,
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn workspace_symbols(
+        &mut self,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, Option<String>, String, PathBuf, Point, Point)>> {
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    defs.name,
+                    defs.kind,
+                    defs.module_path,
+                    files.path,
+                    defs.start_row,
+                    defs.start_column,
+                    defs.end_row,
+                    defs.end_column
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    instr(lower(defs.name), lower(?1)) > 0
+                ORDER BY
+                    length(CAST(defs.name AS BLOB)),
+                    defs.name,
+                    files.path,
+                    defs.start_row
+                LIMIT
+                    ?2
+            ",
+        )?;
+
+        let rows = statement.query_map(&[&query, &(limit as i64)], |row| {
+            (
+                row.get::<usize, String>(0),
+                row.get::<usize, Option<String>>(1),
+                row.get::<usize, String>(2),
+                OsString::from_vec(row.get::<usize, Vec<u8>>(3)).into(),
+                Point::new(row.get(4), row.get(5)),
+                Point::new(row.get(6), row.get(7)),
+            )
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn definitions(&mut self) -> Result<Vec<NamedDefinition>> {
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    defs.name,
+                    defs.module_path,
+                    defs.kind,
+                    files.path,
+                    defs.name_start_row,
+                    defs.name_start_column,
+                    defs.source_line
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id
+            ",
+        )?;
+
+        let rows = statement.query_map(&[], |row| NamedDefinition {
+            name: row.get(0),
+            module_path: row.get(1),
+            kind: row.get(2),
+            path: OsString::from_vec(row.get::<usize, Vec<u8>>(3)).into(),
+            position: Point::new(row.get(4), row.get(5)),
+            source_line: row.get(6),
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    // Finds the definitions named by a path of module names separated by `::`,
+    // like `a::b::c`. The path may be partial, matching any module path that
+    // ends with its modules. Definitions whose module path matches exactly are
+    // returned first.
+    pub fn definitions_at_module_path(
+        &mut self,
+        symbol_path: &str,
+    ) -> Result<Vec<(PathBuf, Point, usize, String)>> {
+        let mut components = symbol_path.split("::").collect::<Vec<_>>();
+        let name = components.pop().unwrap_or_default();
+        let module_path = components.iter().map(|c| c.to_string() + "\t").collect::<String>();
+        let module_path_suffix = "\t".to_owned() + &module_path;
+        let module_path_filter = if module_path.is_empty() {
+            None
+        } else {
+            Some(module_path.as_str())
+        };
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    files.path,
+                    defs.name_start_row,
+                    defs.name_start_column,
+                    length(CAST(defs.name AS BLOB)),
+                    defs.module_path
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    defs.name = ?1 AND
+                    (
+                        ?2 IS NULL OR
+                        defs.module_path = ?2 OR
+                        substr(defs.module_path, -length(?3)) = ?3
+                    )
+            ",
+        )?;
+
+        let rows = statement.query_map(&[&name, &module_path_filter, &module_path_suffix], |row| {
+            (
+                PathBuf::from(OsString::from_vec(row.get::<usize, Vec<u8>>(0))),
+                Point::new(row.get(1), row.get(2)),
+                row.get::<usize, i64>(3) as usize,
+                row.get::<usize, String>(4),
+            )
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        result.sort_by(|a, b| {
+            let a_key = (a.3 != module_path, a.3.matches('\t').count());
+            let b_key = (b.3 != module_path, b.3.matches('\t').count());
+            a_key.cmp(&b_key).then_with(|| (&a.0, a.1).cmp(&(&b.0, b.1)))
+        });
+
+        Ok(result
+            .into_iter()
+            .map(|(path, position, length, module_path)| {
+                (path, position, length, module_path.trim_end_matches('\t').replace('\t', "::"))
+            })
+            .collect())
+    }
+
+    // Returns definitions along with the number of references to their
+    // names, most referenced first. The module prefix is a list of module
+    // names separated by `::`. References only count towards definitions
+    // whose names differ in case if both are in case-insensitive languages.
+    pub fn reference_counts(
+        &mut self,
+        module_prefix: Option<&str>,
+        kind: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String, Option<String>, i64)>> {
+        let module_prefix = module_prefix.map(|prefix| prefix.replace("::", "\t") + "\t");
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    defs.name,
+                    defs.module_path,
+                    defs.kind,
+                    COUNT(*) AS count
+                FROM
+                    defs,
+                    refs,
+                    files AS def_files,
+                    files AS ref_files
+                WHERE
+                    def_files.id = defs.file_id AND
+                    ref_files.id = refs.file_id AND
+                    (
+                        refs.name = defs.name OR (
+                            refs.name_fold = defs.name_fold AND
+                            instr(?4, ',' || def_files.language || ',') > 0 AND
+                            instr(?4, ',' || ref_files.language || ',') > 0
+                        )
+                    ) AND
+                    (?1 IS NULL OR instr(defs.module_path, ?1) = 1) AND
+                    (?2 IS NULL OR defs.kind = ?2)
+                GROUP BY
+                    defs.name,
+                    defs.module_path,
+                    defs.kind
+                ORDER BY
+                    count DESC,
+                    defs.name
+                LIMIT
+                    ?3
+            ",
+        )?;
+
+        let case_insensitive_languages = self.case_insensitive_language_list();
+        let rows = statement.query_map(
+            &[&module_prefix, &kind, &(limit as i64), &case_insensitive_languages],
+            |row| {
+                (
+                    row.get::<usize, String>(0),
+                    row.get::<usize, String>(1),
+                    row.get::<usize, Option<String>>(2),
+                    row.get::<usize, i64>(3),
+                )
+            },
+        )?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn stats(&mut self, dir_path: Option<&Path>) -> Result<IndexStats> {
+        let dir_path = dir_path.map(|path| path.as_os_str().as_bytes().to_vec());
+        let dir_prefix = dir_path.as_ref().map(|path| {
+            let mut prefix = path.clone();
+            prefix.push(b'/');
+            prefix
+        });
+        self.read(|store| store.stats_in_dir(&dir_path, &dir_prefix))
+    }
+
+    fn stats_in_dir(
+        &mut self,
+        dir_path: &Option<Vec<u8>>,
+        dir_prefix: &Option<Vec<u8>>,
+    ) -> Result<IndexStats> {
+        let mut stats_by_language = BTreeMap::new();
+
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    language,
+                    COUNT(*),
+                    coalesce(SUM(error_count > 0), 0)
+                FROM
+                    files
+                WHERE
+                    ?1 IS NULL OR path = ?1 OR instr(path, ?2) = 1
+                GROUP BY
+                    language
+            ",
+        )?;
+        let rows = statement.query_map(&[dir_path, dir_prefix], |row| {
+            (
+                row.get::<usize, Option<String>>(0),
+                row.get::<usize, i64>(1),
+                row.get::<usize, i64>(2),
+            )
+        })?;
+        for row in rows {
+            let (language, count, error_count) = row?;
+            let stats = stats_by_language
+                .entry(language.clone())
+                .or_insert_with(|| LanguageStats::new(language));
+            stats.files = count;
+            stats.files_with_errors = error_count;
+        }
+
+        for table in ["defs", "refs", "local_defs", "local_refs"].iter() {
+            let mut statement = self.db.prepare_cached(&format!(
+                "
+                    SELECT
+                        files.language,
+                        COUNT(*)
+                    FROM
+                        files,
+                        {table}
+                    WHERE
+                        files.id = {table}.file_id AND
+                        (?1 IS NULL OR files.path = ?1 OR instr(files.path, ?2) = 1)
+                    GROUP BY
+                        files.language
+                ",
+                table = table
+            ))?;
+            let rows = statement.query_map(&[dir_path, dir_prefix], |row| {
+                (row.get::<usize, Option<String>>(0), row.get::<usize, i64>(1))
+            })?;
+            for row in rows {
+                let (language, count) = row?;
+                let stats = stats_by_language
+                    .entry(language.clone())
+                    .or_insert_with(|| LanguageStats::new(language));
+                match *table {
+                    "defs" => stats.definitions = count,
+                    "refs" => stats.references = count,
+                    "local_defs" => stats.local_definitions = count,
+                    _ => stats.local_references = count,
+                }
+            }
+        }
+
+        let reference_names = self.db.query_row(
+            "
+                SELECT
+                    COUNT(*)
+                FROM (
+                    SELECT DISTINCT
+                        refs.file_id,
+                        refs.name
+                    FROM
+                        files,
+                        refs
+                    WHERE
+                        files.id = refs.file_id AND
+                        (?1 IS NULL OR files.path = ?1 OR instr(files.path, ?2) = 1)
+                )
+            ",
+            &[dir_path, dir_prefix],
+            |row| row.get::<usize, i64>(0),
+        )?;
+
+        let mut kinds = BTreeMap::new();
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    defs.kind,
+                    COUNT(*)
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    (?1 IS NULL OR files.path = ?1 OR instr(files.path, ?2) = 1)
+                GROUP BY
+                    defs.kind
+            ",
+        )?;
+        let rows = statement.query_map(&[dir_path, dir_prefix], |row| {
+            (row.get::<usize, Option<String>>(0), row.get::<usize, i64>(1))
+        })?;
+        // Definitions whose kind isn't known are counted under `-`.
+        for row in rows {
+            let (kind, count) = row?;
+            kinds.insert(kind.unwrap_or_else(|| "-".to_owned()), count);
+        }
+
+        let languages = stats_by_language
+            .into_iter()
+            .map(|(_, stats)| stats)
+            .collect::<Vec<_>>();
+        Ok(IndexStats {
+            files: languages.iter().map(|stats| stats.files).sum(),
+            files_with_errors: languages.iter().map(|stats| stats.files_with_errors).sum(),
+            definitions: languages.iter().map(|stats| stats.definitions).sum(),
+            references: languages.iter().map(|stats| stats.references).sum(),
+            reference_names,
+            compact_references: self.has_compact_references()?,
+            local_definitions: languages.iter().map(|stats| stats.local_definitions).sum(),
+            local_references: languages.iter().map(|stats| stats.local_references).sum(),
+            kinds,
+            languages,
+        })
+    }
+
+    pub fn search_docs(
+        &mut self,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<(PathBuf, Point, String)>> {
+        let mut statement = self.db.prepare_cached(
+            "
+                SELECT
+                    files.path,
+                    docs.start_row,
+                    docs.start_column,
+                    docs.text
+                FROM
+                    files,
+                    docs
+                WHERE
+                    files.id = docs.file_id AND
+                    instr(docs.text, ?1) > 0
+                ORDER BY
+                    files.path,
+                    docs.start_row
+                LIMIT
+                    ?2
+            ",
+        )?;
+
+        let rows = statement.query_map(&[&query, &(limit as i64)], |row| {
+            (
+                OsString::from_vec(row.get::<usize, Vec<u8>>(0)).into(),
+                Point::new(row.get(1), row.get(2)),
+                row.get::<usize, String>(3),
+            )
+        })?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    // Sort definitions by `location`, or by `kind` or `name` and then by
+    // location. Local definitions have no kind, and are sorted as if their
+    // kind and name were empty.
+    pub fn sort_definitions(
+        &mut self,
+        definitions: &mut Vec<Definition>,
+        order: &str,
+    ) -> Result<()> {
+        let mut keyed_definitions = Vec::new();
+        for definition in definitions.drain(..) {
+            let key = match order {
+                "location" => String::new(),
+                "kind" => definition.kind.clone().unwrap_or_default(),
+                _ => self
+                    .definition_name_and_kind(&definition.path, definition.position)?
+                    .map(|(name, _)| name)
+                    .unwrap_or_default(),
+            };
+            keyed_definitions.push((key, definition));
+        }
+        keyed_definitions.sort_by(|(a_key, a), (b_key, b)| {
+            (a_key, &a.path, a.position).cmp(&(b_key, &b.path, b.position))
+        });
+        definitions.extend(keyed_definitions.into_iter().map(|(_, definition)| definition));
+        Ok(())
+    }
+
+    pub fn definition_name_and_kind(
+        &mut self,
+        path: &Path,
+        position: Point,
+    ) -> Result<Option<(String, Option<String>)>> {
+        let result = self.db.query_row(
+            "
+                SELECT
+                    defs.name,
+                    defs.kind
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    files.path = ?1 AND
+                    defs.name_start_row = ?2 AND
+                    defs.name_start_column = ?3
+            ",
+            &[&path.as_os_str().as_bytes(), &(position.row as i64), &(position.column as i64)],
+            |row| (row.get(0), row.get(1)),
+        );
+
+        match result {
+            Ok(name_and_kind) => Ok(Some(name_and_kind)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn definition_source_line(
+        &mut self,
+        path: &Path,
+        position: Point,
+    ) -> Result<Option<String>> {
+        let result = self.db.query_row(
+            "
+                SELECT
+                    defs.source_line
+                FROM
+                    files,
+                    defs
+                WHERE
+                    files.id = defs.file_id AND
+                    files.path = ?1 AND
+                    defs.name_start_row = ?2 AND
+                    defs.name_start_column = ?3
+            ",
+            &[&path.as_os_str().as_bytes(), &(position.row as i64), &(position.column as i64)],
+            |row| row.get(0),
+        );
+
+        match result {
+            Ok(source_line) => Ok(source_line),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn reference_name(&mut self, path: &Path, position: Point) -> Result<Option<String>> {
+        let result = self.db.query_row(
+            "
+                SELECT
+                    refs.name
+                FROM
+                    files,
+                    refs
+                WHERE
+                    files.id = refs.file_id AND
+                    files.path = ?1 AND
+                    refs.row = ?2 AND
+                    refs.column <= ?3 AND
+                    refs.column + refs.length > ?3
+            ",
+            &[&path.as_os_str().as_bytes(), &(position.row as i64), &(position.column as i64)],
+            |row| row.get(0),
+        );
+
+        match result {
+            Ok(name) => Ok(Some(name)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl LanguageStats {
+    fn new(language: Option<String>) -> Self {
+        Self {
+            language,
+            files: 0,
+            files_with_errors: 0,
+            definitions: 0,
+            references: 0,
+            local_definitions: 0,
+            local_references: 0,
+        }
+    }
+
+    pub fn definitions_per_file(&self) -> f64 {
+        if self.files == 0 {
+            0.0
+        } else {
+            self.definitions as f64 / self.files as f64
+        }
+    }
+}
+
+// Each migration brings the schema from one version to the next, and is run
+// in its own transaction. The first one creates the tables. Migrations must
+// not be changed once they are released; new ones are appended instead.
+const SCHEMA_VERSION: usize = 7;
+
+const MIGRATIONS: [&'static str; SCHEMA_VERSION] = [
+    include_str!("./schema.sql"),
+    include_str!("./migrations/2_ref_lengths.sql"),
+    include_str!("./migrations/3_content_hashes.sql"),
+    include_str!("./migrations/4_error_counts.sql"),
+    include_str!("./migrations/5_name_indexes.sql"),
+    include_str!("./migrations/6_optional_kinds.sql"),
+    include_str!("./migrations/7_visibility.sql"),
+];
+
+// In the compact layout, `refs` is a view of the `ref_names` and
+// `ref_positions` tables.
+fn has_compact_references(db: &Connection) -> rusqlite::Result<bool> {
+    db.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'view' AND name = 'refs'",
+        &[],
+        |row| row.get::<usize, i64>(0) > 0,
+    )
+}
+
+fn sql_io_error(error: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+// The queries that look up definitions and references by name. They are
+// kept here so that the tests can check which indexes they use. Names that
+// differ only in case match when both files are in case-insensitive
+// languages, which are given as a `case_insensitive_language_list`.
+const FIND_DEFINITION_QUERY: &'static str = "
+    SELECT DISTINCT
+        files.path,
+        defs.name_start_row,
+        defs.name_start_column,
+        length(CAST(defs.name AS BLOB)),
+        coalesce(defs.receiver_type = ?4, 0),
+        defs.kind,
+        defs.module_path
+    FROM
+        files,
+        defs,
+        refs,
+        files AS ref_files
+    WHERE
+        files.id == defs.file_id AND
+        ref_files.id = refs.file_id AND
+        (
+            defs.name = refs.name OR (
+                defs.name_fold = refs.name_fold AND
+                instr(?6, ',' || files.language || ',') > 0 AND
+                instr(?6, ',' || ref_files.language || ',') > 0
+            )
+        ) AND
+        refs.file_id = ?1 AND
+        refs.row = ?2 AND
+        refs.column <= ?3 AND
+        refs.column + refs.length > ?3
+    ORDER BY
+        5 DESC,
+        defs.file_id = ?1 DESC
+    LIMIT
+        ?5
+";
+
+// A reference with the exact name has either that name or its lowercase form
+// as its folded name, depending on its file's language, so references are
+// always found by their folded names.
+const FIND_USAGES_QUERY: &'static str = "
+    SELECT DISTINCT
+        files.path,
+        refs.row,
+        refs.column,
+        refs.length
+    FROM
+        files,
+        refs
+    WHERE
+        files.id = refs.file_id AND
+        refs.name_fold IN (?1, ?2) AND
+        (
+            refs.name = ?1 OR (
+                ?3 AND
+                refs.name_fold = ?2 AND
+                instr(?4, ',' || files.language || ',') > 0
+            )
+        ) AND
+        (?5 IS NULL OR files.path = ?5 OR instr(files.path, ?6) = 1)
+    ORDER BY
+        files.path,
+        refs.row,
+        refs.column
+";
+
+const DEFINITIONS_NAMED_QUERY: &'static str = "
+    SELECT DISTINCT
+        files.path,
+        defs.name_start_row,
+        defs.name_start_column,
+        length(CAST(defs.name AS BLOB)),
+        defs.kind,
+        defs.name = ?1,
+        files.language
+    FROM
+        files,
+        defs
+    WHERE
+        files.id = defs.file_id AND
+        (defs.name = ?1 OR defs.name_fold = ?2)
+";
+
+// How many candidate definitions are read for each result that
+// `find_definition` returns.
+const FIND_DEFINITION_CANDIDATE_FACTOR: usize = 20;
+
+const CHECKPOINT_ATTEMPTS: usize = 5;
+const CHECKPOINT_RETRY_INTERVAL_MS: u64 = 200;
+
+// The kinds that `resolve_name` prefers, when no kind priority is configured.
+// Names usually refer to types and modules more often than to functions.
+const RESOLVE_KIND_PRIORITY: [&'static str; 10] = [
+    "module",
+    "namespace",
+    "class",
+    "struct",
+    "interface",
+    "trait",
+    "enum",
+    "type",
+    "function",
+    "method",
+];
+
+// The number of directories that separate two files, plus one if they are
+// different files. Files in the same directory have a distance of 1.
+fn path_distance(a: &Path, b: &Path) -> usize {
+    if a == b {
+        return 0;
+    }
+    let a_dir = a.parent().map_or(Vec::new(), |dir| dir.components().collect());
+    let b_dir = b.parent().map_or(Vec::new(), |dir| dir.components().collect());
+    let common_len = a_dir.iter().zip(b_dir.iter()).take_while(|(a, b)| a == b).count();
+    (a_dir.len() - common_len) + (b_dir.len() - common_len) + 1
+}
+
+// Counts the leading modules that two tab-separated module paths share.
+fn module_proximity(a: &str, b: &str) -> usize {
+    a.split_terminator('\t')
+        .zip(b.split_terminator('\t'))
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+// Module paths are stored with a tab after each module's name, and are
+// reported with the names separated by `::`.
+fn join_module_path(module_path: &str) -> String {
+    module_path.trim_end_matches('\t').replace('\t', "::")
+}
+
+// Kinds that aren't in the priority list are ranked after all of the ones
+// that are.
+fn kind_rank<T: AsRef<str>>(kind: Option<&str>, priority: &[T]) -> usize {
+    kind.and_then(|kind| priority.iter().position(|k| k.as_ref() == kind))
+        .unwrap_or(priority.len())
+}
+
+const DATABASE_FILE_SUFFIXES: [&'static str; 3] = ["-journal", "-wal", "-shm"];
+
+pub fn remove_database(path: &Path) -> io::Result<()> {
+    remove_database_side_files(path)?;
+    remove_file_if_exists(path)
+}
+
+// Both databases must be closed. The destination's journal and WAL files are
+// removed first, so that SQLite doesn't replay them into the new database.
+pub fn replace_database(source: &Path, destination: &Path) -> io::Result<()> {
+    remove_database_side_files(destination)?;
+    fs::rename(source, destination)?;
+    remove_database_side_files(source)
+}
+
+fn remove_database_side_files(path: &Path) -> io::Result<()> {
+    for suffix in DATABASE_FILE_SUFFIXES.iter() {
+        let mut side_file_path = path.as_os_str().to_owned();
+        side_file_path.push(suffix);
+        remove_file_if_exists(Path::new(&side_file_path))?;
+    }
+    Ok(())
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl<'a> StoreFile<'a> {
+    pub fn insert_scope(
+        &mut self,
+        parent_id: Option<i64>,
+        kind: Option<&str>,
+        start_position: Point,
+        end_position: Point,
+    ) -> Result<i64> {
+        let mut stmt = self.db.prepare_cached(
+            "
+                INSERT INTO scopes
+                (file_id, parent_id, kind, start_row, start_column, end_row, end_column)
+                VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ",
+        )?;
+        stmt.execute(&[
+            &self.file_id,
+            &parent_id,
+            &kind,
+            &start_position.row,
+            &start_position.column,
+            &end_position.row,
+            &end_position.column,
+        ])?;
+        Ok(self.db.last_insert_rowid())
+    }
+
+    pub fn insert_local_ref(
+        &mut self,
+        scope_id: i64,
+        local_def_id: i64,
+        name: &str,
+        position: Point,
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(
+            "
+                INSERT INTO local_refs
+                (file_id, scope_id, definition_id, row, column, length)
+                VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)
+            ",
+        )?;
+        stmt.execute(&[
+            &self.file_id,
+            &scope_id,
+            &local_def_id,
+            &position.row,
+            &position.column,
+            &(name.as_bytes().len() as i64),
+        ])?;
+        Ok(())
+    }
+
+    pub fn insert_local_def(
+        &mut self,
+        scope_id: i64,
+        name: &str,
+        position: Point,
+        is_hoisted: bool,
+        declared_type: Option<&str>,
+    ) -> Result<i64> {
+        let mut stmt = self.db.prepare_cached(
+            "
+                INSERT INTO local_defs
+                (file_id, scope_id, row, column, length, name, is_hoisted, declared_type)
+                VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ",
+        )?;
+        stmt.execute(&[
+            &self.file_id,
+            &scope_id,
+            &position.row,
+            &position.column,
+            &(name.as_bytes().len() as i64),
+            &name,
+            &is_hoisted,
+            &declared_type,
+        ])?;
+        Ok(self.db.last_insert_rowid())
+    }
+
+    pub fn insert_ref(
+        &mut self,
+        name: &str,
+        position: Point,
+        kind: Option<&str>,
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(
+            "
+                INSERT INTO refs
+                (file_id, name, name_fold, row, column, length, kind)
+                VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ",
+        )?;
+        let name_fold = self.fold_name(name);
+        stmt.execute(&[
+            &self.file_id,
+            &name,
+            &name_fold.as_ref(),
+            &position.row,
+            &position.column,
+            &(name.as_bytes().len() as i64),
+            &kind,
+        ])?;
+        Ok(())
+    }
+
+    pub fn insert_implementation(
+        &mut self,
+        name: &str,
+        position: Point,
+        interface: &str,
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(
+            "
+                INSERT INTO implementations
+                (file_id, row, column, name, interface)
+                VALUES
+                (?1, ?2, ?3, ?4, ?5)
+            ",
+        )?;
+        stmt.execute(&[&self.file_id, &position.row, &position.column, &name, &interface])?;
+        Ok(())
+    }
+
+    pub fn insert_doc(
+        &mut self,
+        text: &str,
+        start_position: Point,
+        end_position: Point,
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(
+            "
+                INSERT INTO docs
+                (file_id, start_row, start_column, end_row, end_column, text)
+                VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)
+            ",
+        )?;
+        stmt.execute(&[
+            &self.file_id,
+            &start_position.row,
+            &start_position.column,
+            &end_position.row,
+            &end_position.column,
+            &text,
+        ])?;
+        Ok(())
+    }
+
+    pub fn insert_def(
+        &mut self,
+        definition: &DefinitionRecord,
+        module_path: &[&str],
+    ) -> Result<()> {
+        let mut module_path_string = String::with_capacity(
+            module_path
+                .iter()
+                .map(|entry| entry.as_bytes().len() + 1)
                 .sum(),
         );
         for entry in module_path {
@@ -230,30 +2441,819 @@ impl<'a> StoreFile<'a> {
                     file_id,
                     start_row, start_column,
                     end_row, end_column,
-                    name, name_start_row, name_start_column,
+                    name, name_fold, name_start_row, name_start_column,
                     kind,
-                    module_path
+                    receiver_type,
+                    visibility,
+                    module_path,
+                    source_line
                 )
                 VALUES
-                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
             ",
         )?;
+        let name_fold = self.fold_name(definition.name);
         stmt.execute(&[
             &self.file_id,
-            &start_position.row,
-            &start_position.column,
-            &end_position.row,
-            &end_position.column,
-            &name,
-            &name_position.row,
-            &name_position.column,
-            &kind,
+            &definition.start_position.row,
+            &definition.start_position.column,
+            &definition.end_position.row,
+            &definition.end_position.column,
+            &definition.name,
+            &name_fold.as_ref(),
+            &definition.name_position.row,
+            &definition.name_position.column,
+            &definition.kind,
+            &definition.receiver_type,
+            &definition.visibility,
             &module_path_string,
+            &definition.source_line,
         ])?;
         Ok(())
     }
 
+    fn fold_name<'b>(&self, name: &'b str) -> Cow<'b, str> {
+        if self.fold_names {
+            Cow::Owned(name.to_lowercase())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    // This is written in the same transaction as the file's contents, so if
+    // indexing is interrupted, the file's previous modification time is kept
+    // and the file is indexed again next time.
+    pub fn set_modified_time(&mut self, mtime: i64) -> Result<()> {
+        self.db.execute(
+            "UPDATE files SET mtime = ?1 WHERE id = ?2",
+            &[&mtime, &self.file_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_error_count(&mut self, error_count: usize) -> Result<()> {
+        self.db.execute(
+            "UPDATE files SET error_count = ?1 WHERE id = ?2",
+            &[&(error_count as i64), &self.file_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_content_hash(&mut self, content_hash: i64) -> Result<()> {
+        self.db.execute(
+            "UPDATE files SET content_hash = ?1 WHERE id = ?2",
+            &[&content_hash, &self.file_id],
+        )?;
+        Ok(())
+    }
+
     pub fn commit(self) -> rusqlite::Result<()> {
         self.db.commit()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TempDir};
+    use rusqlite::types::ToSql;
+
+    #[test]
+    fn test_fast_import_restores_synchronous_setting() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let synchronous = |store: &Store| {
+            store
+                .db
+                .query_row("PRAGMA synchronous", &[], |row| row.get::<usize, i64>(0))
+                .unwrap()
+        };
+        store.db.execute_batch("PRAGMA synchronous = NORMAL").unwrap();
+        assert_eq!(synchronous(&store), 1);
+
+        store.set_fast_import(true).unwrap();
+        assert_eq!(synchronous(&store), 0);
+        store.set_fast_import(true).unwrap();
+        assert_eq!(synchronous(&store), 0);
+        store.set_fast_import(false).unwrap();
+        assert_eq!(synchronous(&store), 1);
+        store.set_fast_import(false).unwrap();
+        assert_eq!(synchronous(&store), 1);
+    }
+
+    #[test]
+    fn test_initialize_rejects_unversioned_databases() {
+        let dir = TempDir::new();
+        let legacy_path = dir.path().join("legacy.sqlite");
+        let mut store = Store::new(legacy_path).unwrap();
+        store
+            .db
+            .execute_batch(include_str!("../test/fixtures/legacy_schema.sql"))
+            .unwrap();
+        store
+            .db
+            .execute("INSERT INTO files (path) VALUES ('a.js')", &[])
+            .unwrap();
+        let error = store.initialize().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Delete the index and rebuild it"));
+
+        // Databases that this version created are reopened without errors.
+        let mut store = test_util::store(&dir);
+        store.initialize().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let threads = (0..4)
+            .map(|i| {
+                let mut store = store.clone().unwrap();
+                let dir_path = dir.path().to_owned();
+                thread::spawn(move || {
+                    for j in 0..25 {
+                        let path = dir_path.join(format!("{}-{}.js", i, j));
+                        let mut file = store.file(&path, "javascript", false).unwrap();
+                        insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+                        file.insert_ref("foo", Point::new(1, 0), Some("call")).unwrap();
+                        file.commit().unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(store.file_paths(dir.path()).unwrap().len(), 100);
+        assert!(store.checkpoint().unwrap());
+        let wal_path = dir.path().join("index.sqlite-wal");
+        assert_eq!(fs::metadata(wal_path).map_or(0, |metadata| metadata.len()), 0);
+    }
+
+    #[test]
+    fn test_reindexing_through_a_clone_removes_stale_rows() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let path = dir.path().join("a.js");
+        let index_file = |store: &mut Store, names: &[&str]| {
+            let mut file = store.file(&path, "javascript", false).unwrap();
+            let scope_id = file
+                .insert_scope(None, None, Point::new(0, 0), Point::new(10, 0))
+                .unwrap();
+            for (row, name) in names.iter().enumerate() {
+                let row = row as u32;
+                insert_def(&mut file, name, Point::new(row, 9), "function", &[]);
+                file.insert_ref(name, Point::new(row, 20), Some("call")).unwrap();
+                let local_def_id = file
+                    .insert_local_def(scope_id, name, Point::new(row, 30), false, None)
+                    .unwrap();
+                file.insert_local_ref(scope_id, local_def_id, name, Point::new(row, 40))
+                    .unwrap();
+            }
+            file.commit().unwrap();
+        };
+
+        // Each clone is a separate connection, which must enable foreign keys
+        // itself for the old rows to be deleted along with the old file.
+        index_file(&mut store.clone().unwrap(), &["foo", "bar"]);
+        index_file(&mut store.clone().unwrap(), &["baz"]);
+
+        let row_counts = |store: &Store| {
+            ["files", "defs", "refs", "scopes", "local_defs", "local_refs"]
+                .iter()
+                .map(|table| {
+                    let query = format!("SELECT count(*) FROM {}", table);
+                    store.db.query_row(&query, &[], |row| row.get::<usize, i64>(0)).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(row_counts(&store), vec![1, 1, 1, 1, 1, 1]);
+        assert!(store.find_symbol("foo", false, 10).unwrap().is_empty());
+        assert_eq!(store.find_symbol("baz", false, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_readers_see_previous_rows_until_reindex_commits() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let path = dir.path().join("a.js");
+        let mut file = store.file(&path, "javascript", false).unwrap();
+        insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+        file.commit().unwrap();
+
+        let mut writer = store.clone().unwrap();
+        let mut file = writer.file(&path, "javascript", false).unwrap();
+        insert_def(&mut file, "bar", Point::new(0, 9), "function", &[]);
+        insert_def(&mut file, "baz", Point::new(1, 9), "function", &[]);
+        assert_eq!(store.symbol_counts(&path).unwrap(), (1, 0));
+        assert_eq!(store.find_symbol("foo", false, 10).unwrap().len(), 1);
+        file.commit().unwrap();
+
+        assert_eq!(store.symbol_counts(&path).unwrap(), (2, 0));
+        assert!(store.find_symbol("foo", false, 10).unwrap().is_empty());
+
+        // A failed read doesn't leave its transaction open.
+        assert!(store.symbol_counts(&dir.path().join("b.js")).is_err());
+        let mut file = writer.file(&path, "javascript", false).unwrap();
+        insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+        file.commit().unwrap();
+        assert_eq!(store.symbol_counts(&path).unwrap(), (1, 0));
+    }
+
+    #[test]
+    fn test_find_definition_deduplicates_locations() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let path = dir.path().join("a.js");
+        let mut file = store.file(&path, "javascript", false).unwrap();
+        for _ in 0..2 {
+            insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+            file.insert_ref("foo", Point::new(1, 0), Some("call")).unwrap();
+        }
+        file.commit().unwrap();
+
+        let (definitions, has_more) = store.find_definition(&path, Point::new(1, 1), 10).unwrap();
+        assert_eq!(
+            definitions
+                .iter()
+                .map(|definition| (definition.path.clone(), definition.position))
+                .collect::<Vec<_>>(),
+            vec![(path.clone(), Point::new(0, 9))]
+        );
+        assert!(!has_more);
+
+        let usages = store.find_usages(&path, Point::new(1, 1), None).unwrap();
+        assert_eq!(usages, vec![location(&path, 1, 0, 3)]);
+    }
+
+    #[test]
+    fn test_definitions_and_references_without_kinds() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let path = dir.path().join("a.js");
+        let mut file = store.file(&path, "javascript", false).unwrap();
+        let definition = DefinitionRecord {
+            name: "foo",
+            name_position: Point::new(0, 9),
+            start_position: Point::new(0, 0),
+            end_position: Point::new(2, 1),
+            kind: None,
+            receiver_type: None,
+            visibility: None,
+            source_line: None,
+        };
+        file.insert_def(&definition, &[]).unwrap();
+        file.insert_ref("foo", Point::new(1, 2), None).unwrap();
+        file.commit().unwrap();
+
+        let (definitions, _) = store.find_definition(&path, Point::new(1, 3), 10).unwrap();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].kind, None);
+        let symbols = store.find_symbol("foo", false, 10).unwrap();
+        assert_eq!(
+            symbols,
+            vec![Definition {
+                path: path.clone(),
+                position: Point::new(0, 9),
+                length: 3,
+                kind: None,
+                module_path: Some(String::new()),
+                proximity: 0,
+            }]
+        );
+        let symbols = store.workspace_symbols("foo", 10).unwrap();
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].1, None);
+        assert_eq!(
+            store.enclosing_definitions(&path, Point::new(1, 2)).unwrap(),
+            vec![("foo".to_owned(), None, Point::new(0, 9), 3)]
+        );
+        let stats = store.stats(None).unwrap();
+        assert_eq!((stats.definitions, stats.references), (1, 1));
+        assert_eq!(stats.kinds.get("-"), Some(&1));
+    }
+
+    #[test]
+    fn test_find_definition_ranking() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let paths = [
+            dir.path().join("other/c.js"),
+            dir.path().join("lib/deep/d.js"),
+            dir.path().join("lib/b.js"),
+            dir.path().join("lib/a.js"),
+        ];
+        for path in paths[0..3].iter() {
+            let mut file = store.file(path, "javascript", false).unwrap();
+            insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+            file.commit().unwrap();
+        }
+        let mut file = store.file(&paths[3], "javascript", false).unwrap();
+        insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+        file.insert_ref("foo", Point::new(5, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+
+        // Definitions in the same file come first, then ones that are closer
+        // in the directory tree.
+        let (definitions, has_more) =
+            store.find_definition(&paths[3], Point::new(5, 1), 10).unwrap();
+        assert_eq!(
+            definitions.into_iter().map(|definition| definition.path).collect::<Vec<_>>(),
+            vec![paths[3].clone(), paths[2].clone(), paths[1].clone(), paths[0].clone()]
+        );
+        assert!(!has_more);
+
+        let (definitions, has_more) =
+            store.find_definition(&paths[3], Point::new(5, 1), 2).unwrap();
+        assert_eq!(
+            definitions.into_iter().map(|definition| definition.path).collect::<Vec<_>>(),
+            vec![paths[3].clone(), paths[2].clone()]
+        );
+        assert!(has_more);
+    }
+
+    #[test]
+    fn test_find_definition_prefers_same_file_among_many_candidates() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        for i in 0..(FIND_DEFINITION_CANDIDATE_FACTOR * 3) {
+            let mut file = store
+                .file(&dir.path().join(format!("other/{}.js", i)), "javascript", false)
+                .unwrap();
+            insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+            file.commit().unwrap();
+        }
+        let path = dir.path().join("a.js");
+        let mut file = store.file(&path, "javascript", false).unwrap();
+        insert_def(&mut file, "foo", Point::new(3, 9), "function", &[]);
+        file.insert_ref("foo", Point::new(5, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+
+        let (definitions, has_more) = store.find_definition(&path, Point::new(5, 1), 1).unwrap();
+        assert_eq!(
+            definitions
+                .into_iter()
+                .map(|definition| (definition.path, definition.position))
+                .collect::<Vec<_>>(),
+            vec![(path.clone(), Point::new(3, 9))]
+        );
+        assert!(has_more);
+    }
+
+    #[test]
+    fn test_overlapping_local_references_use_the_shortest() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let path = dir.path().join("a.js");
+        let mut file = store.file(&path, "javascript", false).unwrap();
+        let scope_id = file.insert_scope(None, None, Point::new(0, 0), Point::new(9, 0)).unwrap();
+        let long_def_id = file
+            .insert_local_def(scope_id, "abcdef", Point::new(0, 4), false, None)
+            .unwrap();
+        let short_def_id = file
+            .insert_local_def(scope_id, "ab", Point::new(1, 4), false, None)
+            .unwrap();
+        file.insert_local_ref(scope_id, long_def_id, "abcdef", Point::new(2, 0)).unwrap();
+        file.insert_local_ref(scope_id, short_def_id, "ab", Point::new(2, 0)).unwrap();
+        file.insert_local_ref(scope_id, short_def_id, "ab", Point::new(3, 0)).unwrap();
+        file.commit().unwrap();
+
+        let (definitions, _) = store.find_definition(&path, Point::new(2, 1), 10).unwrap();
+        assert_eq!(
+            definitions
+                .into_iter()
+                .map(|definition| (definition.path, definition.position, definition.length))
+                .collect::<Vec<_>>(),
+            vec![(path.clone(), Point::new(1, 4), 2)]
+        );
+        let (definitions, _) = store.find_definition(&path, Point::new(2, 4), 10).unwrap();
+        assert_eq!(
+            definitions.into_iter().map(|definition| definition.position).collect::<Vec<_>>(),
+            vec![Point::new(0, 4)]
+        );
+
+        let usages = store.find_usages(&path, Point::new(2, 1), None).unwrap();
+        assert_eq!(usages, vec![location(&path, 2, 0, 2), location(&path, 3, 0, 2)]);
+    }
+
+    #[test]
+    fn test_sort_definitions() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let a_path = dir.path().join("a.js");
+        let b_path = dir.path().join("b.js");
+        let mut file = store.file(&a_path, "javascript", false).unwrap();
+        insert_def(&mut file, "zeta", Point::new(0, 6), "class", &[]);
+        insert_def(&mut file, "beta", Point::new(3, 9), "function", &[]);
+        file.commit().unwrap();
+        let mut file = store.file(&b_path, "javascript", false).unwrap();
+        insert_def(&mut file, "alpha", Point::new(1, 9), "function", &[]);
+        file.commit().unwrap();
+
+        let definition = |path: &Path, row, column, kind: &str| Definition {
+            path: path.to_owned(),
+            position: Point::new(row, column),
+            length: 4,
+            kind: Some(kind.to_owned()),
+            module_path: Some(String::new()),
+            proximity: 0,
+        };
+        let mut definitions = vec![
+            definition(&b_path, 1, 9, "function"),
+            definition(&a_path, 3, 9, "function"),
+            definition(&a_path, 0, 6, "class"),
+        ];
+        fn positions(definitions: &[Definition]) -> Vec<(PathBuf, Point)> {
+            definitions
+                .iter()
+                .map(|definition| (definition.path.clone(), definition.position))
+                .collect()
+        }
+
+        store.sort_definitions(&mut definitions, "location").unwrap();
+        assert_eq!(
+            positions(&definitions),
+            vec![
+                (a_path.clone(), Point::new(0, 6)),
+                (a_path.clone(), Point::new(3, 9)),
+                (b_path.clone(), Point::new(1, 9)),
+            ]
+        );
+        store.sort_definitions(&mut definitions, "name").unwrap();
+        assert_eq!(
+            positions(&definitions),
+            vec![
+                (b_path.clone(), Point::new(1, 9)),
+                (a_path.clone(), Point::new(3, 9)),
+                (a_path.clone(), Point::new(0, 6)),
+            ]
+        );
+        store.sort_definitions(&mut definitions, "kind").unwrap();
+        assert_eq!(
+            positions(&definitions),
+            vec![
+                (a_path.clone(), Point::new(0, 6)),
+                (a_path.clone(), Point::new(3, 9)),
+                (b_path.clone(), Point::new(1, 9)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delete_files_within_directory() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let paths = ["src/foo", "src/foo/a.js", "src/foo/lib/b.js", "src/foobar/c.js", "src/d.js"]
+            .iter()
+            .map(|path| dir.path().join(path))
+            .collect::<Vec<_>>();
+        for path in paths.iter() {
+            let mut file = store.file(path, "javascript", false).unwrap();
+            insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+            file.commit().unwrap();
+        }
+
+        store.delete_files(&dir.path().join("src/foo")).unwrap();
+        let mut remaining_paths = store.file_paths(dir.path()).unwrap();
+        remaining_paths.sort();
+        assert_eq!(
+            remaining_paths,
+            vec![dir.path().join("src/d.js"), dir.path().join("src/foobar/c.js")]
+        );
+        assert_eq!(store.find_symbol("foo", false, 10).unwrap().len(), 2);
+
+        store.delete_files(&dir.path().join("src/d.js")).unwrap();
+        assert_eq!(store.file_paths(dir.path()).unwrap(), vec![dir.path().join("src/foobar/c.js")]);
+    }
+
+    #[test]
+    fn test_name_lookups_use_indexes() {
+        let dir = TempDir::new();
+        let store = test_util::store(&dir);
+        let query_plan = |query: &str, params: &[&dyn ToSql]| {
+            let mut statement = store.db.prepare(&format!("EXPLAIN QUERY PLAN {}", query)).unwrap();
+            let rows = statement.query_map(params, |row| row.get::<usize, String>(3)).unwrap();
+            rows.map(|row| row.unwrap()).collect::<Vec<_>>().join("\n")
+        };
+        let no_path: Option<Vec<u8>> = None;
+        let languages = ",pascal,";
+
+        // A reference is found by its position, and then its definitions by
+        // their names or their folded names.
+        let plan = query_plan(FIND_DEFINITION_QUERY, &[&1, &0, &0, &no_path, &10, &languages]);
+        assert!(plan.contains("USING INDEX sqlite_autoindex_refs_1"), "{}", plan);
+        assert!(plan.contains("USING INDEX def_names"), "{}", plan);
+        assert!(plan.contains("USING INDEX def_name_folds"), "{}", plan);
+
+        let plan = query_plan(
+            FIND_USAGES_QUERY,
+            &[&"Foo", &"foo", &true, &languages, &no_path, &no_path],
+        );
+        assert!(plan.contains("USING INDEX ref_name_folds"), "{}", plan);
+
+        let plan = query_plan(DEFINITIONS_NAMED_QUERY, &[&"Foo", &"foo"]);
+        assert!(plan.contains("USING INDEX def_names"), "{}", plan);
+        assert!(plan.contains("USING INDEX def_name_folds"), "{}", plan);
+    }
+
+    #[test]
+    fn test_compact_references() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let a_path = dir.path().join("a.js");
+        let b_path = dir.path().join("b.js");
+        let mut file = store.file(&a_path, "javascript", false).unwrap();
+        for row in 0..3 {
+            file.insert_ref("foo", Point::new(row, 0), Some("call")).unwrap();
+        }
+        file.insert_ref("bar", Point::new(3, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+        let mut file = store.file(&b_path, "javascript", false).unwrap();
+        insert_def(&mut file, "foo", Point::new(0, 9), "function", &[]);
+        file.insert_ref("foo", Point::new(1, 0), None).unwrap();
+        file.commit().unwrap();
+
+        let usages = store.find_usages(&a_path, Point::new(0, 1), None).unwrap();
+        assert_eq!(usages.len(), 4);
+        let stats = store.stats(None).unwrap();
+        assert_eq!((stats.references, stats.reference_names), (5, 3));
+        assert!(!stats.compact_references);
+
+        assert!(store.compact_references().unwrap());
+        assert!(!store.compact_references().unwrap());
+        let row_count = |store: &Store, table: &str| {
+            let query = format!("SELECT count(*) FROM {}", table);
+            store.db.query_row(&query, &[], |row| row.get::<usize, i64>(0)).unwrap()
+        };
+        assert_eq!(row_count(&store, "ref_names"), 3);
+        assert_eq!(row_count(&store, "ref_positions"), 5);
+
+        // Queries see the same references as before.
+        assert_eq!(store.find_usages(&a_path, Point::new(0, 1), None).unwrap(), usages);
+        let (definitions, _) = store.find_definition(&a_path, Point::new(2, 1), 10).unwrap();
+        assert_eq!(definitions[0].path, b_path);
+        let stats = store.stats(None).unwrap();
+        assert_eq!((stats.references, stats.reference_names), (5, 3));
+        assert!(stats.compact_references);
+
+        // References are still replaced when a file is re-indexed, and
+        // deleted along with their files.
+        let mut file = store.file(&a_path, "javascript", false).unwrap();
+        file.insert_ref("foo", Point::new(0, 0), Some("call")).unwrap();
+        file.insert_ref("foo", Point::new(1, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+        assert_eq!(store.symbol_counts(&a_path).unwrap(), (0, 2));
+        assert_eq!(row_count(&store, "ref_names"), 2);
+        store.delete_file(&b_path).unwrap();
+        assert_eq!(row_count(&store, "ref_names"), 1);
+        assert_eq!(row_count(&store, "ref_positions"), 2);
+        assert_eq!(store.repair().unwrap(), 0);
+
+        // The layout is kept when the index is opened again.
+        let mut store = test_util::store(&dir);
+        assert!(store.has_compact_references().unwrap());
+        assert_eq!(store.stats(None).unwrap().references, 2);
+    }
+
+    #[test]
+    fn test_find_usages_within_path() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let paths = [
+            dir.path().join("lib/a.js"),
+            dir.path().join("lib/b.js"),
+            dir.path().join("library/c.js"),
+        ];
+        for path in paths.iter() {
+            let mut file = store.file(path, "javascript", false).unwrap();
+            file.insert_ref("foo", Point::new(0, 0), Some("call")).unwrap();
+            file.commit().unwrap();
+        }
+        let usage_paths = |usages: Vec<Location>| {
+            usages.into_iter().map(|usage| usage.path).collect::<Vec<_>>()
+        };
+
+        let usages = store.find_usages(&paths[0], Point::new(0, 1), None).unwrap();
+        assert_eq!(usage_paths(usages), paths.to_vec());
+
+        let lib_path = dir.path().join("lib");
+        let usages = store.find_usages(&paths[0], Point::new(0, 1), Some(&lib_path)).unwrap();
+        assert_eq!(usage_paths(usages), paths[0..2].to_vec());
+
+        let usages = store.find_usages(&paths[0], Point::new(0, 1), Some(&paths[2])).unwrap();
+        assert_eq!(usage_paths(usages), paths[2..].to_vec());
+    }
+
+    #[test]
+    fn test_name_lookups_only_fold_case_for_case_insensitive_languages() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        store.set_case_insensitive_languages(vec!["pascal".to_owned()]);
+        let pascal_path = dir.path().join("a.pas");
+        let mut file = store.file(&pascal_path, "pascal", true).unwrap();
+        insert_def(&mut file, "WriteLine", Point::new(0, 10), "procedure", &[]);
+        file.commit().unwrap();
+        let javascript_path = dir.path().join("b.js");
+        let mut file = store.file(&javascript_path, "javascript", false).unwrap();
+        insert_def(&mut file, "writeline", Point::new(0, 9), "function", &[]);
+        file.commit().unwrap();
+
+        let resolved_paths = |store: &mut Store, name| {
+            let mut paths = store
+                .resolve_name(name, 10)
+                .unwrap()
+                .into_iter()
+                .map(|definition| definition.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(resolved_paths(&mut store, "WRITELINE"), vec![pascal_path.clone()]);
+        assert_eq!(resolved_paths(&mut store, "WriteLine"), vec![pascal_path.clone()]);
+        assert_eq!(
+            resolved_paths(&mut store, "writeline"),
+            vec![pascal_path.clone(), javascript_path.clone()]
+        );
+
+        let named_paths = |store: &mut Store, name, fold_case| {
+            let (definitions, has_more) = store.definitions_named(name, fold_case, 10).unwrap();
+            assert!(!has_more);
+            let mut paths = definitions
+                .into_iter()
+                .map(|definition| definition.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(named_paths(&mut store, "writeline", false), vec![javascript_path.clone()]);
+        assert_eq!(
+            named_paths(&mut store, "writeline", true),
+            vec![pascal_path.clone(), javascript_path.clone()]
+        );
+        assert_eq!(named_paths(&mut store, "WRITELINE", true), vec![pascal_path.clone()]);
+        assert!(named_paths(&mut store, "WRITELINE", false).is_empty());
+
+        let (definitions, has_more) = store.definitions_named("writeline", true, 1).unwrap();
+        assert_eq!((definitions.len(), has_more), (1, true));
+        let (definitions, has_more) = store.definitions_named("writeline", false, 1).unwrap();
+        assert_eq!((definitions.len(), has_more), (1, false));
+    }
+
+    #[test]
+    fn test_references_only_fold_case_for_case_insensitive_languages() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        store.set_case_insensitive_languages(vec!["pascal".to_owned()]);
+        let pascal_path = dir.path().join("a.pas");
+        let javascript_path = dir.path().join("b.js");
+
+        let mut file = store.file(&pascal_path, "pascal", true).unwrap();
+        insert_def(&mut file, "WriteLine", Point::new(0, 10), "procedure", &[]);
+        file.insert_ref("WRITELINE", Point::new(1, 0), Some("call")).unwrap();
+        file.insert_ref("log", Point::new(2, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+        let mut file = store.file(&javascript_path, "javascript", false).unwrap();
+        insert_def(&mut file, "Log", Point::new(0, 9), "function", &[]);
+        file.insert_ref("writeline", Point::new(1, 0), Some("call")).unwrap();
+        file.insert_ref("Log", Point::new(2, 0), Some("call")).unwrap();
+        file.commit().unwrap();
+
+        // A Pascal reference finds the Pascal definition regardless of case,
+        // but not the JavaScript definition, and vice versa.
+        let definition_paths = |store: &mut Store, path: &Path, row| {
+            let (definitions, _) = store.find_definition(path, Point::new(row, 1), 10).unwrap();
+            definitions.into_iter().map(|d| d.path).collect::<Vec<_>>()
+        };
+        assert_eq!(definition_paths(&mut store, &pascal_path, 1), vec![pascal_path.clone()]);
+        assert!(definition_paths(&mut store, &pascal_path, 2).is_empty());
+        assert!(definition_paths(&mut store, &javascript_path, 1).is_empty());
+        assert_eq!(
+            definition_paths(&mut store, &javascript_path, 2),
+            vec![javascript_path.clone()]
+        );
+
+        let usages = store.find_usages(&pascal_path, Point::new(0, 11), None).unwrap();
+        assert_eq!(usages, vec![location(&pascal_path, 1, 0, 9)]);
+        let usages = store.find_usages(&javascript_path, Point::new(0, 10), None).unwrap();
+        assert_eq!(usages, vec![location(&javascript_path, 2, 0, 3)]);
+
+        let unresolved = store
+            .unresolved_references(None)
+            .unwrap()
+            .into_iter()
+            .map(|(path, position, name, _)| (path, position.row, name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unresolved,
+            vec![
+                (pascal_path.clone(), 2, "log".to_owned()),
+                (javascript_path.clone(), 1, "writeline".to_owned()),
+            ]
+        );
+
+        let counts = store
+            .reference_counts(None, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _, _, count)| (name, count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![("Log".to_owned(), 1), ("WriteLine".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn test_resolve_name_ranking() {
+        let dir = TempDir::new();
+        let mut store = test_util::store(&dir);
+        let definitions = [
+            ("lib/deeply/nested/function.js", "function", vec![]),
+            ("nested-class.js", "class", vec!["outer"]),
+            ("class.js", "class", vec![]),
+            ("lib/class.js", "class", vec![]),
+        ];
+        for (path, kind, module_path) in definitions.iter() {
+            let mut file = store.file(&dir.path().join(path), "javascript", false).unwrap();
+            insert_def(&mut file, "Foo", Point::new(0, 6), kind, module_path);
+            file.commit().unwrap();
+        }
+        let mut file = store.file(&dir.path().join("a.js"), "javascript", false).unwrap();
+        let definition = DefinitionRecord {
+            name: "Foo",
+            name_position: Point::new(0, 14),
+            start_position: Point::new(0, 0),
+            end_position: Point::new(0, 20),
+            kind: Some("class"),
+            receiver_type: None,
+            visibility: Some("private"),
+            source_line: None,
+        };
+        file.insert_def(&definition, &[]).unwrap();
+        file.commit().unwrap();
+
+        // More significant kinds come first, then public definitions, then
+        // definitions nested in fewer modules, then ones with shorter paths.
+        let resolved = store.resolve_name("Foo", 10).unwrap();
+        assert_eq!(
+            resolved
+                .iter()
+                .map(|d| (d.path.strip_prefix(dir.path()).unwrap(), d.kind.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Path::new("class.js"), Some("class".to_owned())),
+                (Path::new("lib/class.js"), Some("class".to_owned())),
+                (Path::new("nested-class.js"), Some("class".to_owned())),
+                (Path::new("a.js"), Some("class".to_owned())),
+                (Path::new("lib/deeply/nested/function.js"), Some("function".to_owned())),
+            ]
+        );
+
+        assert_eq!(store.resolve_name("Foo", 1).unwrap().len(), 1);
+        assert!(store.resolve_name("Bar", 10).unwrap().is_empty());
+
+        // A configured kind priority replaces the default one.
+        store.set_kind_priority(vec!["function".to_owned(), "class".to_owned()]);
+        let resolved = store.resolve_name("Foo", 10).unwrap();
+        assert_eq!(
+            resolved
+                .iter()
+                .map(|definition| definition.path.strip_prefix(dir.path()).unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                Path::new("lib/deeply/nested/function.js"),
+                Path::new("class.js"),
+                Path::new("lib/class.js"),
+                Path::new("nested-class.js"),
+                Path::new("a.js"),
+            ]
+        );
+    }
+
+    fn location(path: &Path, row: u32, column: u32, length: usize) -> Location {
+        Location {
+            path: path.to_owned(),
+            position: Point::new(row, column),
+            length,
+        }
+    }
+
+    fn insert_def(
+        file: &mut StoreFile,
+        name: &str,
+        name_position: Point,
+        kind: &str,
+        module_path: &[&str],
+    ) {
+        let definition = DefinitionRecord {
+            name,
+            name_position,
+            start_position: Point::new(name_position.row, 0),
+            end_position: Point::new(name_position.row, name_position.column + 10),
+            kind: Some(kind),
+            receiver_type: None,
+            visibility: None,
+            source_line: None,
+        };
+        file.insert_def(&definition, module_path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use crate::language_registry::LanguageRegistry;
+use crate::store::Store;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+// Tags function declarations as definitions, and the functions that are
+// called by call expressions as references.
+pub const JAVASCRIPT_PROPERTY_SHEET_JSON: &'static str = r#"{
+  "states": [
+    {
+      "property_set_id": 0,
+      "default_next_state_id": 0,
+      "transitions": [
+        {"type": "function_declaration", "named": true, "state_id": 1},
+        {"type": "call_expression", "named": true, "state_id": 3}
+      ]
+    },
+    {
+      "property_set_id": 1,
+      "default_next_state_id": 0,
+      "transitions": [{"type": "identifier", "named": true, "state_id": 2}]
+    },
+    {"property_set_id": 2, "default_next_state_id": 0, "transitions": []},
+    {
+      "property_set_id": 0,
+      "default_next_state_id": 0,
+      "transitions": [{"type": "identifier", "named": true, "index": 0, "state_id": 4}]
+    },
+    {"property_set_id": 3, "default_next_state_id": 0, "transitions": []}
+  ],
+  "property_sets": [
+    {},
+    {"definition": "true", "definition-type": "function"},
+    {"definition-part": "name"},
+    {"reference": "true", "reference-type": "call"}
+  ]
+}"#;
+
+static NEXT_TEMP_DIR_ID: AtomicUsize = AtomicUsize::new(0);
+static COMPILE_JAVASCRIPT: Once = Once::new();
+
+// A directory that is removed, along with its contents, when it is dropped.
+// Tests run in parallel, so each one gets its own directory.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let id = NEXT_TEMP_DIR_ID.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("tree-tags-test-{}-{}", process::id(), id));
+        fs::create_dir_all(&path).unwrap();
+        // Crawled paths are canonicalized, so the directory's path should be
+        // too, in case the temporary directory is behind a symlink.
+        let path = fs::canonicalize(path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&self, relative_path: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(relative_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+pub fn store(dir: &TempDir) -> Store {
+    let mut store = Store::new(dir.path().join("index.sqlite")).unwrap();
+    store.initialize().unwrap();
+    store
+}
+
+// Grammars for the tests are cloned by `script/fetch-fixtures`.
+pub fn fixture_grammar_path(name: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test/fixtures/grammars")
+        .join(format!("tree-sitter-{}", name));
+    assert!(
+        path.join("src/parser.c").exists(),
+        "The {} grammar is missing. Run script/fetch-fixtures.",
+        name
+    );
+    path
+}
+
+// Returns a registry with a copy of the JavaScript grammar, which handles
+// `.js` files using the given property sheet, or no property sheet at all.
+// The compiled library is shared by all of the tests.
+pub fn javascript_registry(dir: &TempDir, property_sheet_json: Option<&str>) -> LanguageRegistry {
+    let parsers_path = dir.path().join("parsers");
+    let grammar_path = parsers_path.join("tree-sitter-javascript");
+    copy_dir(&fixture_grammar_path("javascript").join("src"), &grammar_path.join("src"));
+    let definitions_json_path = grammar_path.join("src/definitions.json");
+    match property_sheet_json {
+        Some(property_sheet_json) => fs::write(definitions_json_path, property_sheet_json).unwrap(),
+        None => {
+            fs::remove_file(definitions_json_path).ok();
+        }
+    }
+    let package_json = serde_json::json!({ "tree-sitter": { "file-types": ["js"] } });
+    fs::write(grammar_path.join("package.json"), package_json.to_string()).unwrap();
+
+    let compiled_parsers_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/test-parsers");
+    let mut registry = LanguageRegistry::new(compiled_parsers_path, vec![parsers_path]);
+    registry.load_parsers().unwrap();
+    COMPILE_JAVASCRIPT.call_once(|| registry.compile_languages().unwrap());
+    registry
+}
+
+fn copy_dir(source: &Path, destination: &Path) {
+    fs::create_dir_all(destination).unwrap();
+    for entry in fs::read_dir(source).unwrap() {
+        let entry = entry.unwrap();
+        let destination = destination.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &destination);
+        } else {
+            fs::copy(entry.path(), destination).unwrap();
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Keeps the index up to date with a directory while it is being edited. The
+// watcher debounces events, so a file that is written several times in quick
+// succession is only re-indexed once. Each changed file is indexed in its
+// own transaction, just as it would be by `index`.
+
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+use tree_tags::crawler::{DirCrawler, Result};
+
+const DEBOUNCE_DELAY_MS: u64 = 200;
+
+pub fn watch(crawler: &mut DirCrawler, dir_path: &Path) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let delay = Duration::from_millis(DEBOUNCE_DELAY_MS);
+    let mut watcher = notify::watcher(sender, delay).map_err(notify_io_error)?;
+    watcher
+        .watch(dir_path, RecursiveMode::Recursive)
+        .map_err(notify_io_error)?;
+    eprintln!("Watching {}", dir_path.display());
+
+    crawler.enable_tree_cache();
+
+    for event in receiver.iter() {
+        let paths = match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Remove(path) => vec![path],
+            DebouncedEvent::Rename(old_path, new_path) => vec![old_path, new_path],
+            DebouncedEvent::Rescan => {
+                crawler.crawl_path(dir_path.to_owned())?;
+                Vec::new()
+            }
+            DebouncedEvent::Error(error, _) => {
+                eprintln!("Watch error: {}", error);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+
+        for path in paths {
+            match crawler.update_path(&path) {
+                Ok(()) => {}
+                Err(ref e) if e.is_file_specific() => {
+                    eprintln!("Skipping {}: {}", path.display(), e);
+                }
+                Err(e) => return Err(e.with_path(&path)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn notify_io_error(error: notify::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}